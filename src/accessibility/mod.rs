@@ -0,0 +1,182 @@
+use zbus::blocking::{connection, Connection, Proxy};
+use zbus::zvariant::OwnedObjectPath;
+
+const REGISTRY_NAME: &str = "org.a11y.atspi.Registry";
+const ROOT_PATH: &str = "/org/a11y/atspi/accessible/root";
+const ACCESSIBLE_INTERFACE: &str = "org.a11y.atspi.Accessible";
+const COMPONENT_INTERFACE: &str = "org.a11y.atspi.Component";
+
+// AtspiRole values of the objects worth hinting
+pub const ROLE_CHECK_BOX: u32 = 7;
+pub const ROLE_CHECK_MENU_ITEM: u32 = 8;
+pub const ROLE_COMBO_BOX: u32 = 11;
+pub const ROLE_LIST_ITEM: u32 = 32;
+pub const ROLE_MENU_ITEM: u32 = 35;
+pub const ROLE_PAGE_TAB: u32 = 37;
+pub const ROLE_PASSWORD_TEXT: u32 = 40;
+pub const ROLE_PUSH_BUTTON: u32 = 43;
+pub const ROLE_RADIO_BUTTON: u32 = 44;
+pub const ROLE_RADIO_MENU_ITEM: u32 = 45;
+pub const ROLE_SLIDER: u32 = 51;
+pub const ROLE_SPIN_BUTTON: u32 = 52;
+pub const ROLE_TEXT: u32 = 61;
+pub const ROLE_TOGGLE_BUTTON: u32 = 62;
+pub const ROLE_ENTRY: u32 = 79;
+pub const ROLE_LINK: u32 = 88;
+pub const ROLE_TREE_ITEM: u32 = 91;
+
+// top level windows without decorations, the popups of an application
+const ROLE_WINDOW: u32 = 69;
+
+const ACTIONABLE_ROLES: [u32; 17] = [
+    ROLE_CHECK_BOX,
+    ROLE_CHECK_MENU_ITEM,
+    ROLE_COMBO_BOX,
+    ROLE_LIST_ITEM,
+    ROLE_MENU_ITEM,
+    ROLE_PAGE_TAB,
+    ROLE_PASSWORD_TEXT,
+    ROLE_PUSH_BUTTON,
+    ROLE_RADIO_BUTTON,
+    ROLE_RADIO_MENU_ITEM,
+    ROLE_SLIDER,
+    ROLE_SPIN_BUTTON,
+    ROLE_TEXT,
+    ROLE_TOGGLE_BUTTON,
+    ROLE_ENTRY,
+    ROLE_LINK,
+    ROLE_TREE_ITEM,
+];
+
+// AtspiStateType bits in the first word of GetState
+const STATE_ACTIVE: u32 = 1;
+const STATE_SHOWING: u32 = 25;
+
+// screen coordinates for GetExtents
+const COORD_TYPE_SCREEN: u32 = 0;
+
+// bus name and path of an object in the tree
+pub type ObjectRef = (String, OwnedObjectPath);
+
+#[derive(Debug, Clone)]
+pub struct AccessibleElement {
+    pub role: u32,
+    // x, y, width, height in screen coordinates
+    pub extents: (i32, i32, i32, i32),
+}
+
+// Connects to the accessibility bus, which is separate from the session bus
+pub fn connect() -> Result<Connection, Box<dyn std::error::Error>> {
+    let session = Connection::session()?;
+    let bus = Proxy::new(&session, "org.a11y.Bus", "/org/a11y/bus", "org.a11y.Bus")?;
+    let address: String = bus.call("GetAddress", &())?;
+    log::debug!("Bus at {}", address);
+    connect_to(address.as_str())
+}
+
+pub fn connect_to(address: &str) -> Result<Connection, Box<dyn std::error::Error>> {
+    Ok(connection::Builder::address(address)?.build()?)
+}
+
+fn accessible<'a>(conn: &Connection, name: &'a str, path: &'a OwnedObjectPath) -> zbus::Result<Proxy<'a>> {
+    Proxy::new(conn, name, path.as_str(), ACCESSIBLE_INTERFACE)
+}
+
+pub fn applications(conn: &Connection) -> zbus::Result<Vec<(String, OwnedObjectPath)>> {
+    let root = Proxy::new(conn, REGISTRY_NAME, ROOT_PATH, ACCESSIBLE_INTERFACE)?;
+    root.call("GetChildren", &())
+}
+
+// first word of the state and the role of an object
+fn state_and_role(conn: &Connection, (bus, path): &(String, OwnedObjectPath)) -> zbus::Result<(u32, u32)> {
+    let proxy = accessible(conn, bus, path)?;
+    let state: Vec<u32> = proxy.call("GetState", &())?;
+    Ok((state.first().copied().unwrap_or(0), proxy.call("GetRole", &())?))
+}
+
+// The application whose top level window is active, with that window and the popups (menus,
+// tooltips) it has open, the parts of it that are on top of the screen. Toolkits mark the
+// focused window active, so this also works on Wayland, where clients cannot see which window
+// has the focus.
+pub fn focused_windows(conn: &Connection) -> zbus::Result<Option<(String, Vec<ObjectRef>)>> {
+    for (name, path) in applications(conn)? {
+        let application = accessible(conn, &name, &path)?;
+        let windows: Vec<(String, OwnedObjectPath)> = match application.call("GetChildren", &()) {
+            Ok(windows) => windows,
+            Err(_) => continue,
+        };
+        let mut active = false;
+        let mut focused = Vec::new();
+        for window in windows {
+            let (state, role) = match state_and_role(conn, &window) {
+                Ok(state_and_role) => state_and_role,
+                Err(_) => continue,
+            };
+            if state & (1 << STATE_ACTIVE) != 0 {
+                active = true;
+                focused.push(window);
+            } else if state & (1 << STATE_SHOWING) != 0 && role == ROLE_WINDOW {
+                focused.push(window);
+            }
+        }
+        if active {
+            return Ok(Some((application.get_property::<String>("Name")?, focused)));
+        }
+    }
+    Ok(None)
+}
+
+// Name of the application whose top level window is active
+pub fn active_application(conn: &Connection) -> zbus::Result<Option<String>> {
+    Ok(focused_windows(conn)?.map(|(name, _)| name))
+}
+
+// Walks the tree below an application or window depth first, skipping subtrees that are not
+// showing, until the deadline or max_nodes is reached. Every object is queried on the bus name
+// it was listed with, embedded widgets can live on another connection than the application.
+pub fn collect_actionable(conn: &Connection, root: &(String, OwnedObjectPath), deadline: std::time::Instant, max_nodes: usize) -> Vec<AccessibleElement> {
+    let (name, path) = root;
+    let mut elements = Vec::new();
+    let mut stack: Vec<(String, OwnedObjectPath)> = match accessible(conn, name, path).and_then(|proxy| proxy.call::<_, _, Vec<(String, OwnedObjectPath)>>("GetChildren", &())) {
+        Ok(children) => children,
+        Err(err) => {
+            log::warn!("Failed to list {}: {}", name, err);
+            return elements;
+        }
+    };
+
+    let mut visited = 0;
+    while let Some((bus, path)) = stack.pop() {
+        if visited >= max_nodes || std::time::Instant::now() > deadline {
+            log::debug!("Budget exhausted for {} after {:?} nodes", name, visited);
+            break;
+        }
+        visited += 1;
+
+        let proxy = match accessible(conn, &bus, &path) {
+            Ok(proxy) => proxy,
+            Err(_) => continue,
+        };
+        let state: Vec<u32> = match proxy.call("GetState", &()) {
+            Ok(state) => state,
+            Err(_) => continue,
+        };
+        if state.first().map_or(true, |bits| bits & (1 << STATE_SHOWING) == 0) {
+            continue;
+        }
+
+        let role: u32 = proxy.call("GetRole", &()).unwrap_or(0);
+        if ACTIONABLE_ROLES.contains(&role) {
+            let extents = Proxy::new(conn, bus.as_str(), path.as_str(), COMPONENT_INTERFACE)
+                .and_then(|component| component.call::<_, _, (i32, i32, i32, i32)>("GetExtents", &(COORD_TYPE_SCREEN,)));
+            if let Ok(extents) = extents {
+                elements.push(AccessibleElement { role, extents });
+            }
+        }
+
+        if let Ok(children) = proxy.call::<_, _, Vec<(String, OwnedObjectPath)>>("GetChildren", &()) {
+            stack.extend(children);
+        }
+    }
+    elements
+}
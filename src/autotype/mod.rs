@@ -40,7 +40,7 @@ pub async fn start_autoclick_session() -> Result<(), Box<dyn std::error::Error>>
             write_token(&token)?;
         }
         None => {
-            println!("No token found");
+            log::debug!("No token found");
         }
     }
     let session = RemoteDesktopSession {
@@ -65,7 +65,7 @@ pub async fn movemouse(x: i32, y: i32, screen_width: i32, screen_height: i32) ->
 pub async fn click(click_type: ClickType) {
     let key = match click_type {
         ClickType::Left => {
-            println!("Left click");
+            log::debug!("Left click");
             272
         }
         ClickType::Right => {
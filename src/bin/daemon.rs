@@ -1,561 +1,446 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 #![allow(rustdoc::missing_crate_level_docs)]
 
-use std::cmp;
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixStream;
 use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 
-use color_space::Hsv;
-use color_space::Rgb;
-use rayon::iter::IndexedParallelIterator;
-use rayon::iter::IntoParallelRefIterator;
-use rayon::iter::ParallelIterator;
+use swiftmouse::accessibility;
+use swiftmouse::config;
+use swiftmouse::detection;
+use swiftmouse::detection::Element;
+use swiftmouse::detection::ElementKind;
 use swiftmouse::image_utils;
+use swiftmouse::logging;
+use swiftmouse::overlay;
 use swiftmouse::screenshot;
 use swiftmouse::globalshortcut;
-use zbus::zvariant::Endian;
-use zbus::zvariant::WriteBytes;
+use swiftmouse::trace;
+use swiftmouse::tracker;
+use swiftmouse::windows;
 
 mod gui;
 
-const SCREENSHOT_PATH: &str = "/tmp/screenshot.png";
-
-fn get_pixel_gray(image: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, mut x: i16, mut y: i16) -> u16 {
-    if x < 0 {
-        x = 0;
-    }
-    if y < 0 {
-        y = 0;
-    }
-    if x >= image.width() as i16 {
-        x = image.width() as i16 - 1;
-    }
-    if y >= image.height() as i16 {
-        y = image.height() as i16 - 1;
-    }
-
-    let pixel = image.get_pixel(x as u32, y as u32);
-    return pixel.0[0] as u16 + pixel.0[1] as u16 + pixel.0[2] as u16;
-}
+// number of hint labels, lines get three letters, the other lists two
+const TWO_LETTERS: u32 = 26 * 26;
+const THREE_LETTERS: u32 = 26 * 26 * 26;
 
 #[tokio::main]
 async fn main() {
-    let (mut rx, _conn) = globalshortcut::listen().await;
-    println!("[Main] Waiting for events");
+    logging::init();
+    let config = config::load();
+    logging::configure(&config.logging);
+    log::debug!("Config: {:?}", config);
+    let mut registry = detection::Registry::with_defaults(&config);
+    registry.configure(&config.detectors);
+    log::debug!("Detectors: {:?}", registry.names());
+    let mut tracker = tracker::Tracker::load(&config.labels);
+    let mut frame_cache = detection::cache::FrameCache::new(config.cache.tile_size);
+    // the token of the running detection job, the D-Bus listener cancels it
+    let job = Arc::new(Mutex::new(detection::CancellationToken::new()));
+    let (mut rx, _conn) = globalshortcut::listen(job.clone()).await;
+    log::info!("Waiting for events");
     let mut screenshot_tool = screenshot::get_screenshot_tool();
     let screenshot = screenshot_tool.take_screenshot().await.unwrap();
 
    
-    while let Some(_) = rx.recv().await {
-        let total_start = std::time::Instant::now();
-        let screenshot_start = std::time::Instant::now();
-        let screenshot: image::ImageBuffer<image::Rgb<u8>, Vec<u8>> = screenshot_tool.take_screenshot().await.unwrap();
-        println!("Screenshot Elapsed: {:?}", screenshot_start.elapsed());
-        // let dynamic_image = image::DynamicImage::ImageRgb8(screenshot.clone());
-        // write to /tmp/screenshot.png
-        screenshot.save("/tmp/screenshot.png").unwrap();
-        let start = std::time::Instant::now();
-        
-        //gray image
-        // let gray_image = dynamic_image.grayscale();
-        // println!("Gray Elapsed: {:?}", start.elapsed());
-        // 3x3 edge conv
-        let start = std::time::Instant::now();
-        let downsampled_map = vec![vec![false; (screenshot.width() / 2) as usize]; (screenshot.width() / 2) as usize];
-        let downsampled_map = downsampled_map.par_iter().enumerate().map(|(x, row)| {
-            let mut line = vec![false; row.len()];
-            for y in 0..row.len() {
-                let mut field = vec![vec![0; 4]; 4];
-                for i in -1..3 {
-                    for j in -1..3 {
-                        field[(i+1) as usize][(j+1) as usize] = get_pixel_gray(&screenshot, (x as i16)*2 + i, (y as i16)*2 + j);
-                    }
-                }
-
-                let mut tl_edge: i16 = 0;
-                for i in 0..3 {
-                    for j in 0..3 {
-                        if i == 1 && j == 1 {
-                            tl_edge += field[i][j] as i16 * 8;
-                        } else {
-                            tl_edge -= field[i][j] as i16;
-                        }
-                    }
-                }
-                if tl_edge > 0 {
-                    line[y] = true;
-                    continue;
-                }
-
-
-                let mut tr_edge: i16 = 0;
-                for i in 0..3 {
-                    for j in 0..3 {
-                        if i == 1 && j == 1 {
-                            tr_edge += field[i+1][j] as i16 * 8;
-                        } else {
-                            tr_edge -= field[i+1][j] as i16;
-                        }
-                    }
-                }
-                if tr_edge > 0 {
-                    line[y] = true;
-                    continue;
-                }
-
-                let mut bl_edge: i16 = 0;
-                for i in 0..3 {
-                    for j in 0..3 {
-                        if i == 1 && j == 1 {
-                            bl_edge += field[i][j+1] as i16 * 8;
-                        } else {
-                            bl_edge -= field[i][j+1] as i16;
-                        }
-                    }
-                }
-                if  bl_edge > 0 {
-                    line[y] = true;
-                    continue;
-                }
-
-                let mut br_edge: i16 = 0;
-                for i in 0..3 {
-                    for j in 0..3 {
-                        if i == 1 && j == 1 {
-                            br_edge += field[i+1][j+1] as i16 * 8;
-                        } else {
-                            br_edge -= field[i+1][j+1] as i16;
+    let mut next_prefetch = std::time::Instant::now();
+    let mut overlay = Overlay::new();
+    loop {
+        // With prefetch the screen is checked in the background and detected again when it
+        // changed, so a Run finds the result in the cache
+        let trigger = if config.prefetch.enabled && config.cache.enabled {
+            tokio::select! {
+                trigger = rx.recv() => trigger,
+                _ = tokio::time::sleep_until(next_prefetch.into()) => {
+                    let start = std::time::Instant::now();
+                    // Only a running PipeWire stream, starting it or the fallbacks would show a
+                    // portal dialog. The overlay would be detected while it is shown. A Run
+                    // cancels the job, so it does not wait for the prefetch to finish.
+                    if !overlay.is_visible() {
+                        let cancel = new_job(&job);
+                        if let Ok(capture) = screenshot_tool.take_capture_running().await {
+                            if !cancel.is_cancelled() {
+                                detect(&config, &registry, &mut frame_cache, &mut screenshot_tool, &capture.image, capture.luma, &cancel, true, &mut |_: &[Element]| {}).await;
+                            }
                         }
                     }
-                }
-                if br_edge > 0 {
-                    line[y] = true;
+                    // keep the share of cpu time spent in the background below the budget
+                    let elapsed = start.elapsed();
+                    let budget_wait = elapsed.div_f32(config.prefetch.cpu_budget.max(0.01)).saturating_sub(elapsed);
+                    next_prefetch = std::time::Instant::now() + budget_wait.max(std::time::Duration::from_millis(config.prefetch.interval_ms));
                     continue;
                 }
             }
-            line
-        }).collect::<Vec<Vec<bool>>>();
-        println!("Downsampled Elapsed: {:?}", start.elapsed());
-
-        // to debug img
-        // let mut downsampled_image = image::DynamicImage::new_rgb8(screenshot.width()/2, screenshot.height()/2);
-        // for x in 0..downsampled_image.width() {
-        //     for y in 0..downsampled_image.height() {
-        //         if downsampled_map[x as usize][y as usize] {
-        //             downsampled_image.put_pixel(x, y, image::Rgba([255, 255, 255, 255]));
-        //         } else {
-        //             downsampled_image.put_pixel(x, y, image::Rgba([0, 0, 0, 255]));
-        //         }
-        //     }
-        // }
-        // // save downsampled image
-        // downsampled_image.save("/tmp/downsampled.png").unwrap();
-
-
-        // let start = std::time::Instant::now();
-        // let mut edge_image = gray_image.filter3x3(&[
-        //     -1.0, -1.0, -1.0,
-        //     -1.0, 8.0, -1.0,
-        //     -1.0, -1.0, -1.0,
-        // ]);
-        // println!("Edge Elapsed: {:?}", start.elapsed());
-        // save edge image
-        // edge_image.save("/tmp/edge.png").unwrap();
-        // if > 0 then 1
-        // for x in 0..edge_image.width() {
-        //     for y in 0..edge_image.height() {
-        //         let pixel = edge_image.get_pixel(x, y);
-        //         if pixel[0] > 0 {
-        //             edge_image.put_pixel(x, y, image::Rgba([255, 255, 255, 255]));
-        //         } else {
-        //             edge_image.put_pixel(x, y, image::Rgba([0, 0, 0, 255]));
-        //         }
-        //     }
-        // }
-        // save edge image
-        // edge_image.save("/tmp/edge2.png").unwrap();
-        // downsample to half  size manually
-        // let start = std::time::Instant::now();
-        // let downsampled_image_map = downsampled_map;
-        // let mut downsampled_image_map = vec![vec![false; (edge_image.width() / 2) as usize]; (edge_image.width() / 2) as usize];
-        // let mut downsampled_image1 = image::DynamicImage::new_rgb8(edge_image.width()/2, edge_image.height()/2);
-        // for x in 0..edge_image.width()/2 {
-        //     for y in 0..edge_image.height()/2 {
-        //         // if one pixel is > 0
-        //         if (
-        //             edge_image.get_pixel(x*2, y*2)[0] > 0 ||
-        //             edge_image.get_pixel(x*2+1, y*2)[0] > 0 ||
-        //             edge_image.get_pixel(x*2, y*2+1)[0] > 0 ||
-        //             edge_image.get_pixel(x*2+1, y*2+1)[0] > 0
-        //         ) {
-        //             downsampled_image_map[x as usize][y as usize] = true;
-        //             downsampled_image1.put_pixel(x, y, image::Rgba([255, 255, 255, 255]));
-        //         } else {
-        //             downsampled_image_map[x as usize][y as usize] = false;
-        //             downsampled_image1.put_pixel(x, y, image::Rgba([0, 0, 0, 255]));
-        //         }
-        //     }
-        // }
-        // println!("Downsampled Elapsed: {:?}", start.elapsed());
-        // // save downsampled image
-        // downsampled_image1.save("/tmp/downsampled1.png").unwrap();
-        // map to true fals
-        // for x in 0..downsampled_image.width() {
-        //     for y in 0..downsampled_image.height() {
-        //         let pixel = downsampled_image.get_pixel(x, y);
-        //         if pixel[0] > 0 {
-        //             downsampled_image_map[x as usize][y as usize] = true;
-        //         }
-        //     }
-        // }
-        let downsampled_image_map = downsampled_map;
-
-        let start = std::time::Instant::now();
-        let num_threads = rayon::current_num_threads();
-        // println!("Num threads: {:?}", num_threads);
-        let width = downsampled_image_map.len();
-        let num_chunks = num_threads;
-        let chunk_size = width / num_chunks;
-        let mut chunks: Vec<(usize, usize)> = Vec::new();
-        loop {
-            let start = chunks.len() * chunk_size;
-            let end = start + chunk_size;
-            if end >= width {
-                chunks.push((start, width-1));
-                break;
-            }
-            chunks.push((start, end));
+        } else {
+            rx.recv().await
+        };
+        let trigger = match trigger {
+            Some(trigger) => trigger,
+            None => break,
+        };
+        // every stage of the run is recorded in its trace, see the Latency D-Bus method
+        let trace_id = trace::begin(if trigger.mode == globalshortcut::Mode::Windows { "windows" } else { "elements" }, trigger.time_us);
+        trace::record("dispatch", trigger.time_us);
+        // a trigger while the overlay is open starts over on the current screen
+        let start = trace::now_us();
+        overlay.hide().await;
+        trace::record("hide_overlay", start);
+        if trigger.mode == globalshortcut::Mode::Windows {
+            show_windows(&mut screenshot_tool, &mut overlay, trace_id).await;
+            trace::end();
+            continue;
         }
-        println!("Chunks: {:?}", chunks);
-        let boxes = chunks.par_iter().enumerate().map(|(i, (start, end))| {
-            let width = downsampled_image_map.len();
-            let height = downsampled_image_map[0].len();
-            let mut visited = vec![vec![false; height]; width as usize];
-            let mut boxes = Vec::new();
-            for x in *start..*end {
-                for y in 0..height {
-                    if downsampled_image_map[x][y] && !visited[x][y] {
-                        let mut start_x = x;
-                        let mut end_x = x;
-                        let mut start_y = y;
-                        let mut end_y = y;
-
-                        let mut queue = std::collections::VecDeque::new();
-                        queue.push_back((x, y));
-                        visited[x][y] = true;
-                        while let Some((x, y)) = queue.pop_front() {
-                            if x < start_x {
-                                start_x = x;
-                            }
-                            if x > end_x {
-                                end_x = x;
-                            }
-                            if y < start_y {
-                                start_y = y;
-                            }
-                            if y > end_y {
-                                end_y = y;
-                            }
-
-                            if x > 0 && downsampled_image_map[x - 1][y] && !visited[x - 1][y] {
-                                queue.push_back((x-1, y));
-                                visited[x - 1][y] = true;
-                            }
-                            if x < width - 1 && downsampled_image_map[x + 1][y] && !visited[x + 1][y] {
-                                queue.push_back((x+1, y));
-                                visited[x + 1][y] = true;
-                            }
-                            if y > 0 && downsampled_image_map[x][y - 1] && !visited[x][y - 1] {
-                                queue.push_back((x, y-1));
-                                visited[x][y - 1] = true;
-                            }
-                            if y < height - 1 && downsampled_image_map[x][y + 1] && !visited[x][y + 1] {
-                                queue.push_back((x, y+1));
-                                visited[x][y + 1] = true;
-                            }
-                        }
-
-                        // println!("Chunk {:?} Box: {:?} {:?} {:?} {:?}", i, start_x, start_y, end_x, end_y);
-                        boxes.push((cmp::max(start_x as i32 -1, 0) as usize,
-                         cmp::max(start_y as i32 -1, 0) as usize,
-                         cmp::min(end_x as i32 +1, width as i32 -1) as usize,
-                         cmp::min(end_y as i32 +1, height as i32 -1) as usize));
-                    }
-                }
+        let total_start = std::time::Instant::now();
+        let start = trace::now_us();
+        let capture = if config.settle.enabled {
+            screenshot_tool.take_settled_capture(&config.settle).await
+        } else {
+            screenshot_tool.take_capture().await
+        };
+        let capture = match capture {
+            Ok(capture) => capture,
+            Err(err) => {
+                log::warn!("Failed to capture the screen: {}", err);
+                trace::end();
+                continue;
             }
-            boxes
-        }).collect::<Vec<Vec<(usize, usize, usize, usize)>>>().concat();
-        println!("Boxes Elapsed: {:?}", start.elapsed());
-
-        let start = std::time::Instant::now();
-        let big_boxes = boxes.clone().into_iter().filter(|(min_x, min_y, max_x, max_y)| {
-            (max_y - min_y) > 15
-        }).collect::<Vec<(usize, usize, usize, usize)>>();
-        println!("Big box Elapsed: {:?}", start.elapsed());
-
-        let start = std::time::Instant::now();
-        let large_images = boxes.clone().into_iter().filter(|(min_x, min_y, max_x, max_y)| {
-            (max_y - min_y) > 300 && (max_x - min_x) > 300 && *min_x > 10 && *max_x < width - 10
-        }).collect::<Vec<(usize, usize, usize, usize)>>();
-        println!("large img Elapsed: {:?}", start.elapsed());
-
-        let start = std::time::Instant::now();
-        let text_boxes = boxes.clone().into_iter().filter(|(min_x, min_y, max_x, max_y)| {
-            (max_y - min_y) <= 15
-        }).filter(|(min_x, min_y, max_x, max_y)| {
-            let mut in_video = false;
-            for video in &large_images {
-                if *min_x >= video.0 && *min_y >= video.1 && *max_x <= video.2 && *max_y <= video.3 {
-                    in_video = true;
-                    break;
-                }
+        };
+        let screenshot = capture.image;
+        trace::record("capture", start);
+        let start = trace::now_us();
+        let frame = match screenshot::SharedFrame::new(&screenshot) {
+            Ok(frame) => frame,
+            Err(err) => {
+                log::warn!("Failed to share the frame: {}", err);
+                trace::end();
+                continue;
             }
-            !in_video
-        }).collect::<Vec<(usize, usize, usize, usize)>>();
-        println!("Video filter Elapsed: {:?}", start.elapsed());
+        };
+        trace::record("share_frame", start);
+        let start = trace::now_us();
+        let app = focused_application();
+        trace::record("application", start);
+
+        // the overlay starts with the screenshot, hints are streamed to it as the detectors finish
+        overlay.show_elements(frame, trace_id);
+        let cancel = new_job(&job);
+        let start = trace::now_us();
+        let elements = detect(&config, &registry, &mut frame_cache, &mut screenshot_tool, &screenshot, capture.luma, &cancel, false, &mut |elements: &[Element]| {
+            overlay.update(elements, &mut tracker, &app, &screenshot);
+        }).await;
+        trace::record("detect", start);
+        overlay.finish();
+        if cancel.is_cancelled() {
+            tracker.discard();
+            log::debug!("Detection cancelled after {:?}", total_start.elapsed());
+        } else {
+            tracker.commit();
+            tracker.save();
+            log::debug!("Num elements: {:?}", elements.len());
+        }
+        trace::end();
+    }
 
-        let start = std::time::Instant::now();
-        // create lines by merging text boxes that are close on x and aligned on 1
-        let mut lines: Vec<Vec<(usize,usize,usize,usize)>> = Vec::new();
-        let mut text_boxes: Vec<(usize, usize, usize, usize)> = text_boxes.clone();
-        let mut handled: Vec<i32> = vec![-1; text_boxes.len()];
-        for i in 0..text_boxes.len() {
-           if handled[i] == -1 {
-                let mut line = Vec::new();
-                line.push(text_boxes[i]);
-                lines.push(line);
-                handled[i] = lines.len() as i32 - 1;
-            }
+    log::info!("Exiting");
+}
 
-            for j in 0..text_boxes.len() {
-                if handled[j] != -1 {
-                    continue;
-                }
-                let (min_x, min_y, max_x, max_y) = text_boxes[j];
-                let (min_x1, min_y1, max_x1, max_y1) = text_boxes[i];
-                if (min_y1 as i32 - min_y as i32).abs() <= 3 && ((max_x1 as i32 - min_x as i32).abs() <= 4 || (max_x as i32 - min_x1 as i32).abs() <= 4) {
-                    lines[handled[i] as usize].push(text_boxes[j]);
-                    handled[j] = handled[i];
-                }
-            }
+// Runs the detectors on a frame, or takes the elements from the cache where the frame did not
+// change since the last run. Only the elements found on the pixels are cached, the detectors
+// with other sources run every time. In the background (prefetch) the foreground only
+// detectors are skipped.
+async fn detect(config: &config::Config, registry: &detection::Registry, frame_cache: &mut detection::cache::FrameCache, screenshot_tool: &mut screenshot::ScreenshotTool, screenshot: &detection::Screenshot, luma: Option<detection::edges::LumaPlane>, cancel: &detection::CancellationToken, background: bool, on_update: &mut dyn FnMut(&[Element])) -> Vec<Element> {
+    let start = trace::now_us();
+    let hashes = if config.cache.enabled {
+        detection::cache::tile_hashes(screenshot, frame_cache.tile_size())
+    } else {
+        Vec::new()
+    };
+    let lookup = if config.cache.enabled {
+        frame_cache.lookup(screenshot.dimensions(), &hashes)
+    } else {
+        detection::cache::Lookup::Miss
+    };
+    trace::record("cache_lookup", start);
+    match lookup {
+        detection::cache::Lookup::Unchanged { elements, components, frame } => {
+            log::debug!("Screen unchanged, reusing {:?} elements", elements.len());
+            on_update(&elements);
+            let mut context = detection::DetectionContext::merged(screenshot, config, cancel, &frame, components);
+            context.background = background;
+            registry.run_detectors(&context, elements, detection::Phase::Sources, on_update)
         }
-
-        // create bounding box per line
-        let mut line_boxes = Vec::new();
-        for line in lines {
-            let mut min_x = line[0].0;
-            let mut min_y = line[0].1;
-            let mut max_x = line[0].2;
-            let mut max_y = line[0].3;
-            for (x, y, x1, y1) in line {
-                if x < min_x {
-                    min_x = x;
-                }
-                if y < min_y {
-                    min_y = y;
-                }
-                if x1 > max_x {
-                    max_x = x1;
-                }
-                if y1 > max_y {
-                    max_y = y1;
-                }
+        lookup => {
+            // a second frame to find regions that are playing or animating
+            let motion_frame = if config.media.motion {
+                let start = trace::now_us();
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                let motion_frame = if background {
+                    screenshot_tool.take_capture_running().await.ok().map(|capture| capture.image)
+                } else {
+                    screenshot_tool.take_screenshot().await.ok()
+                };
+                trace::record("motion_frame", start);
+                motion_frame.filter(|frame| frame.dimensions() == screenshot.dimensions())
+            } else {
+                None
+            };
+            if cancel.is_cancelled() {
+                return Vec::new();
             }
-            line_boxes.push((min_x, min_y, max_x, max_y));
-        }
-
-        let unmapped_lines = unmap_downsampled_boxes(&line_boxes);
-        // let imgcpy = Arc::new(Mutex::new(screenshot.clone()));
-        let links = unmapped_lines.par_iter().enumerate().map(|(i, (min_x, min_y, max_x, max_y))| {
-            // let mut imgcpy = screenshot.clone();
 
-            // go through each column, convert each pixel to hsv, and check if it intense. 
-            let mut max_values = vec![false; max_x - min_x];
-            for x in *min_x..*max_x {
-                for y in *min_y..*max_y {
-                    let pixel = screenshot.get_pixel(x as u32, y as u32);
-                    let rgb = Rgb::new(pixel.0[0] as f64, pixel.0[1] as f64, pixel.0[2] as f64);
-                    let hsv = Hsv::from(rgb);
-                    let intensity = hsv.s;
-                    if intensity > 0.5 && hsv.h > 200.0 && hsv.h < 270.0 {
-                        max_values[x - min_x] = true;
+            // Only the changed parts of the screen are detected again, the elements outside of
+            // them are taken from the cache and the whole-screen detectors run once on top. When
+            // most of the screen changed, a full pass is cheaper than many regions.
+            if let detection::cache::Lookup::Partial { dirty, regional, components, frame } = lookup {
+                let scale = frame.block * frame.stride;
+                let screen_area = (screenshot.width() * screenshot.height()) as usize;
+                let max_grow = (screen_area as f32 * config.cache.max_grow) as usize;
+                let regions = detection::cache::dirty_regions(&dirty, &regional, config.cache.margin, scale, screenshot.dimensions(), max_grow);
+                let area = regions.iter().map(|region| (region.2 - region.0 + 1) * (region.3 - region.1 + 1)).sum::<usize>();
+                if (area as f32) < screen_area as f32 * config.cache.max_dirty {
+                    let mut regional = detection::cache::outside(&regional, &regions, max_grow);
+                    let mut components = detection::cache::components_outside(&components, &regions, scale, max_grow);
+                    log::debug!("{:?} tiles changed, detecting {:?} regions, reusing {:?} elements", dirty.len(), regions.len(), regional.len());
+                    let (mut found, mut found_components) = registry.run_regions(screenshot, motion_frame.as_ref(), config, cancel, background, &regions, &frame);
+                    regional.append(&mut found);
+                    components.append(&mut found_components);
+                    // a cancelled job stops between regions, the overlay keeps what it has
+                    if cancel.is_cancelled() {
+                        return regional;
                     }
-                }
-            }
-            // println!("Max values {:?}", max_values);
-            // println!("Box {:?}", (min_x, min_y, max_x, max_y));
-            // // draw box
-            // let mut imgcpy = imgcpy.lock().unwrap(); 
-            // for x in *min_x..*max_x {
-            //     if max_values[x - min_x] {
-            //         imgcpy.put_pixel(x as u32, *min_y as u32, image::rgb([255, 0, 0]));
-            //     } else {
-            //         imgcpy.put_pixel(x as u32, *min_y as u32, image::rgb([0, 255, 0]));
-            //     }
-            // }
-            // create boxes from max values, gap of 5 or more is new box, min length is 15
-            let mut boxes = Vec::new();
-            let mut start = -1;
-            let mut end = -1;
-            let mut gap = 0;
-            for i in 0..max_values.len() {
-                if start == -1 {
-                    if max_values[i] {
-                        start = i as i32;
-                        end = i as i32;
-                        gap = 0;
-                    }
-                } else {
-                    if max_values[i] {
-                        end = i as i32;
-                        gap = 0;
-                    } else {
-                        gap += 1;
-                        if gap >= 15 {
-                            if end - start >= 50 {
-                                boxes.push((start as usize + min_x, *min_y, end as usize + min_x, *max_y));
-                            }
-                            start = -1;
-                            end = -1;
-                        }
+                    on_update(&regional);
+                    let mut context = detection::DetectionContext::merged(screenshot, config, cancel, &frame, components);
+                    context.background = background;
+                    let elements = registry.run_detectors(&context, regional.clone(), detection::Phase::Screen, on_update);
+                    if config.cache.enabled && !cancel.is_cancelled() {
+                        frame_cache.store(screenshot.dimensions(), hashes, elements.clone(), regional, context.components.clone(), frame);
                     }
+                    return registry.run_detectors(&context, elements, detection::Phase::Sources, on_update);
                 }
             }
-            // last box
-            if start != -1 && end != -1 && end - start >= 50 {
-                boxes.push((start as usize + min_x, *min_y, end as usize + min_x, *max_y));
+
+            let mut context = detection::DetectionContext::new(screenshot, luma, motion_frame.as_ref(), config, cancel);
+            context.background = background;
+            let regional = registry.run_detectors(&context, Vec::new(), detection::Phase::Regional, on_update);
+            let elements = registry.run_detectors(&context, regional.clone(), detection::Phase::Screen, on_update);
+            log::debug!("Num boxes: {:?}", context.components.len());
+            // a cancelled job only found part of the elements
+            if config.cache.enabled && !cancel.is_cancelled() {
+                frame_cache.store(screenshot.dimensions(), hashes, elements.clone(), regional, context.components.clone(), context.params());
             }
+            registry.run_detectors(&context, elements, detection::Phase::Sources, on_update)
+        }
+    }
+}
 
-            // filter boxes where the # of max values is less than 70% of the width
-            boxes = boxes.into_iter().filter(|(lmin_x, min_y, lmax_x, max_y)| {
-                let mut white = 0;
-                for x in *lmin_x..*lmax_x {
-                    if max_values[x - min_x] {
-                        white += 1;
-                    }
-                }
-                white as f32 / (lmax_x - lmin_x) as f32 > 0.5
-            }).collect::<Vec<(usize, usize, usize, usize)>>();
+// Cancels the previous job, if it is still running, and makes the token of the next one
+fn new_job(job: &Arc<Mutex<detection::CancellationToken>>) -> detection::CancellationToken {
+    let cancel = detection::CancellationToken::new();
+    let mut job = job.lock().unwrap();
+    job.cancel();
+    *job = cancel.clone();
+    cancel
+}
+
+// Labels are kept per application. On X11 that is the class of the focused window, on Wayland
+// the application AT-SPI reports active. Applications that are not accessible there share the
+// "default" labels.
+fn focused_application() -> String {
+    if let Ok(Some(class)) = windows::active_application() {
+        return class;
+    }
+    let application = accessibility::connect().and_then(|connection| Ok(accessibility::active_application(&connection)?));
+    match application {
+        Ok(Some(name)) => name,
+        Ok(None) => "default".to_string(),
+        Err(err) => {
+            log::debug!("No focused application: {}", err);
+            "default".to_string()
+        }
+    }
+}
 
-            // let boxescpy = boxes.clone();
-            // for (min_x, min_y, max_x, max_y) in boxescpy {
-            //     for x in min_x..max_x {
-            //         imgcpy.put_pixel(x as u32, (min_y + 1) as u32, image::Rgb([0, 0, 255]));
-            //     }
-            // }
+async fn show_windows(screenshot_tool: &mut screenshot::ScreenshotTool, overlay: &mut Overlay, trace_id: u32) {
+    let start = trace::now_us();
+    let windows = match windows::list() {
+        Ok(windows) => windows,
+        Err(err) => {
+            log::warn!("Failed to list windows: {}", err);
+            return;
+        }
+    };
+    trace::record("list_windows", start);
+    log::debug!("Windows: {:?}", windows.len());
+    let start = trace::now_us();
+    let screenshot = match screenshot_tool.take_screenshot().await {
+        Ok(screenshot) => screenshot,
+        Err(err) => {
+            log::warn!("Failed to capture the screen: {}", err);
+            return;
+        }
+    };
+    trace::record("capture", start);
+    let start = trace::now_us();
+    let frame = match screenshot::SharedFrame::new(&screenshot) {
+        Ok(frame) => frame,
+        Err(err) => {
+            log::warn!("Failed to share the frame: {}", err);
+            return;
+        }
+    };
+    trace::record("share_frame", start);
+    overlay.show_windows(frame, &windows, trace_id);
+}
 
-            // let name = format!("/tmp/line{}.png", i);
-            // imgcpy.save(name).unwrap();
+// The overlay process is started on the first trigger and stays running, so it does not have to
+// start up and connect to the portals every time, it only opens a window for each show. Its
+// stdin is a socket, commands go to it and events come back on the same socket. Hints are
+// streamed while detection is running, a list is sent again whenever a later detector changed
+// it.
+struct Overlay {
+    child: Option<std::process::Child>,
+    stream: Option<UnixStream>,
+    visible: Arc<AtomicBool>,
+    sent: std::collections::HashMap<u32, Vec<Element>>,
+    // when the elements were shown, the first hints are traced from there
+    shown_us: u64,
+}
 
-            boxes
-        }).collect::<Vec<Vec<(usize, usize, usize, usize)>>>().concat();
-        println!("links {:?}", links.len());
-        // let mut scp = screenshot.clone();
-        // for (min_x, min_y, max_x, max_y) in links.clone() {
-        //     for x in min_x..max_x {
-        //         for y in min_y..max_y {
-        //             if x >= scp.width() as usize || y >= scp.height() as usize {
-        //                 continue;
-        //             }
-        //             scp.put_pixel( x as u32, y as u32, image::Rgb([255, 0, 0]));
-        //         }
-        //     }
-        // }
-        // let scp = imgcpy.lock().unwrap();
-        // scp.save("/tmp/links.png").unwrap();
+impl Overlay {
+    fn new() -> Self {
+        Overlay { child: None, stream: None, visible: Arc::new(AtomicBool::new(false)), sent: std::collections::HashMap::new(), shown_us: 0 }
+    }
 
+    fn is_visible(&self) -> bool {
+        self.visible.load(Ordering::SeqCst)
+    }
 
-        let start = std::time::Instant::now();
-        // small images are big boxes that are > 50% white
-        let mut small_images = Vec::new();
-        for (min_x, min_y, max_x, max_y) in big_boxes.clone() {
-            let mut white = 0;
-            let mut total = 0;
-            for x in min_x..max_x {
-                for y in min_y..max_y {
-                    if x >= downsampled_image_map.len() as usize || y >= downsampled_image_map[0].len() as usize {
-                        continue;
-                    }
-                    total += 1;
-                    if downsampled_image_map[x][y] {
-                        white += 1;
-                    }
-                }
-            }
-            if white as f32 / total as f32 > 0.5 {
-                small_images.push((min_x, min_y, max_x, max_y));
+    // starts the overlay if it is not running, or exited since it was last used
+    fn start(&mut self) {
+        if let Some(child) = self.child.as_mut() {
+            if let Ok(None) = child.try_wait() {
+                return;
             }
+            log::warn!("Overlay exited, starting it again");
         }
-        println!("Small img Elapsed: {:?}", start.elapsed());
-
+        let mut binpath = std::env::current_exe().unwrap();
+        binpath.set_file_name("gui");
+        log::debug!("GUI binpath: {:?}", binpath);
+        let start = trace::now_us();
+        let (stream, overlay_stream) = UnixStream::pair().unwrap();
+        let child = std::process::Command::new(binpath)
+            .stdin(Stdio::from(OwnedFd::from(overlay_stream)))
+            .spawn()
+            .unwrap();
 
-        println!("Num boxes: {:?}", boxes.len());
-        println!("Num small boxes: {:?}", text_boxes.len());
-        println!("Num big boxes: {:?}", big_boxes.len());
-        println!("Num ultra large boxes: {:?}", large_images.len());
-        println!("Num lines: {:?}", line_boxes.len());
-        println!("Num small images: {:?}", small_images.len());
-        println!("Num links: {:?}", links.len());
-        println!("Total Elapsed: {:?}", total_start.elapsed());
+        let visible = self.visible.clone();
+        let mut events = stream.try_clone().unwrap();
+        std::thread::spawn(move || loop {
+            let mut message = match overlay::read_message(&mut events) {
+                Ok(message) => message,
+                Err(_) => return,
+            };
+            match message.tag {
+                overlay::EVENT_HIDDEN => visible.store(false, Ordering::SeqCst),
+                overlay::EVENT_SPAN => match overlay::read_span(&mut message.payload) {
+                    Ok((id, stage, start_us, end_us)) => trace::record_span(id, &stage, start_us, end_us),
+                    Err(err) => log::warn!("Failed to read an overlay span: {}", err),
+                },
+                event => log::warn!("Unknown overlay event {:?}", event),
+            }
+        });
+        trace::record("start_overlay", start);
+        self.child = Some(child);
+        self.stream = Some(stream);
+        self.visible.store(false, Ordering::SeqCst);
+    }
 
+    fn show_elements(&mut self, frame: screenshot::SharedFrame, trace_id: u32) {
+        self.start();
+        let mut buffer: Vec<u8> = Vec::new();
+        overlay::write_show(&mut buffer, trace_id, frame.width, frame.height);
+        self.sent.clear();
+        self.shown_us = trace::now_us();
+        self.visible.store(true, Ordering::SeqCst);
+        // the memfd is passed with the message, the daemon's copy is closed after sending it
+        self.send(overlay::SHOW_ELEMENTS, &buffer, Some(frame.fd()));
+    }
 
-        // image_utils::draw_boxes(&mut downsampled_image, &text_boxes, image::Rgba([255, 0, 0, 255]));
-        // image_utils::draw_boxes(&mut downsampled_image, &big_boxes, image::Rgba([0, 255, 0, 255]));
-        // image_utils::draw_boxes(&mut downsampled_image, &large_images, image::Rgba([0, 255, 255, 255]));
-        // image_utils::draw_boxes(&mut downsampled_image, &line_boxes, image::Rgba([255, 255, 0, 255]));
-        // image_utils::draw_boxes(&mut downsampled_image, &small_images, image::Rgba([255, 0, 255, 255]));
-        // save downsampled image
-        // downsampled_image.save("/tmp/downsampled2.png").unwrap();
+    fn show_windows(&mut self, frame: screenshot::SharedFrame, windows: &Vec<windows::Window>, trace_id: u32) {
+        self.start();
+        let mut buffer: Vec<u8> = Vec::new();
+        overlay::write_show(&mut buffer, trace_id, frame.width, frame.height);
+        overlay::write_windows(&mut buffer, &windows.iter().map(|window| (window.rect, window.id, window.title.clone())).collect());
+        self.visible.store(true, Ordering::SeqCst);
+        self.send(overlay::SHOW_WINDOWS, &buffer, Some(frame.fd()));
+    }
 
-        let mut binpath = std::env::current_exe().unwrap();
-        binpath.set_file_name("gui");
-        let gui_binpath = binpath.to_str().unwrap();
-        println!("[Main] GUI binpath: {:?}", gui_binpath);
+    // hides the overlay if it is shown and waits until it is gone, so the next screenshot
+    // does not contain it
+    async fn hide(&mut self) {
+        if !self.is_visible() {
+            return;
+        }
+        self.send(overlay::HIDE, &[], None);
+        let start = std::time::Instant::now();
+        while self.is_visible() && start.elapsed() < std::time::Duration::from_millis(200) {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        self.visible.store(false, Ordering::SeqCst);
+    }
 
-        let mut child = std::process::Command::new(gui_binpath)
-            .stdin(Stdio::piped())
-            .spawn()
-            .unwrap();
-        match child.stdin.as_mut() {
-            Some(stdin) => {
-                write_boxes(stdin, &unmap_downsampled_boxes(&big_boxes));
-                write_boxes(stdin, &unmap_downsampled_boxes(&line_boxes));
-                write_boxes(stdin, &unmap_downsampled_boxes(&small_images));
-                write_boxes(stdin, &unmap_downsampled_boxes(&large_images));
-                write_boxes(stdin, &links);
+    fn update(&mut self, elements: &[Element], tracker: &mut tracker::Tracker, app: &str, screenshot: &detection::Screenshot) {
+        // text fields are clicked like boxes
+        let batches = [
+            (overlay::BATCH_BIG_BOXES, "boxes", TWO_LETTERS, vec![ElementKind::BigBox, ElementKind::Field]),
+            (overlay::BATCH_LINES, "lines", THREE_LETTERS, vec![ElementKind::Line]),
+            (overlay::BATCH_SMALL_IMAGES, "images", TWO_LETTERS, vec![ElementKind::SmallImage]),
+            (overlay::BATCH_MEDIA, "", 0, vec![ElementKind::Media]),
+            (overlay::BATCH_LINKS, "links", TWO_LETTERS, vec![ElementKind::Link]),
+            (overlay::BATCH_MEDIA_POINTS, "media", TWO_LETTERS, vec![ElementKind::MediaPoint]),
+            (overlay::BATCH_PINNED, "", 0, vec![ElementKind::Pinned]),
+        ];
+        for (batch, list, capacity, kinds) in batches {
+            let batch_elements = elements.iter().filter(|element| kinds.contains(&element.kind)).cloned().collect::<Vec<Element>>();
+            if self.sent.get(&batch).map_or(batch_elements.is_empty(), |sent| *sent == batch_elements) {
+                continue;
             }
-            None => {
-                println!("[Main] Failed to open stdin");
+            let mut buffer: Vec<u8> = Vec::new();
+            let boxes = batch_elements.iter().map(|element| element.rect).collect::<Vec<(usize, usize, usize, usize)>>();
+            if batch == overlay::BATCH_PINNED {
+                overlay::write_labelled_boxes(&mut buffer, &batch_elements);
+            } else if list.is_empty() {
+                overlay::write_boxes(&mut buffer, &boxes, &(0..boxes.len() as u32).collect::<Vec<u32>>());
+            } else {
+                overlay::write_boxes(&mut buffer, &boxes, &tracker.assign(app, list, capacity, screenshot, &boxes));
             }
+            self.send(batch, &buffer, None);
+            if self.sent.is_empty() {
+                trace::record("first_hints", self.shown_us);
+            }
+            self.sent.insert(batch, batch_elements);
         }
-        // wait for proc exit
-        child.wait().unwrap();
     }
 
-    println!("[Main] Exiting");
-}
-
-fn unmap_downsampled_boxes(boxes: &Vec<(usize, usize, usize, usize)>) -> Vec<(usize, usize, usize, usize)> {
-    let mut new_boxes = Vec::new();
-    for (min_x, min_y, max_x, max_y) in boxes {
-        new_boxes.push((
-            *min_x * 2,
-            *min_y * 2,
-            *max_x * 2,
-            *max_y * 2
-        ));
+    // ends the hints of the show, also when detection was cancelled
+    fn finish(&mut self) {
+        self.send(overlay::BATCH_DONE, &[], None);
     }
-    new_boxes
-}
 
-fn write_boxes(stdin: &mut std::process::ChildStdin, boxes: &Vec<(usize, usize, usize, usize)>) {
-    let boxes_len = boxes.len() as u32;
-    stdin.write_u32(Endian::Little, boxes_len).unwrap();
-    for box_ in boxes {
-        stdin.write_u32(Endian::Little, box_.0 as u32).unwrap();
-        stdin.write_u32(Endian::Little, box_.1 as u32).unwrap();
-        stdin.write_u32(Endian::Little, box_.2 as u32).unwrap();
-        stdin.write_u32(Endian::Little, box_.3 as u32).unwrap();
+    // a failed write means the overlay exited, it is started again on the next trigger
+    fn send(&mut self, tag: u32, payload: &[u8], fd: Option<std::os::fd::RawFd>) {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => return,
+        };
+        if let Err(err) = overlay::write_message(stream, tag, payload, fd) {
+            log::warn!("Overlay closed: {}", err);
+            self.stream = None;
+            self.visible.store(false, Ordering::SeqCst);
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,33 +1,79 @@
-use zbus::zvariant::Endian;
-use zbus::zvariant::ReadBytes;
+use std::os::fd::AsFd;
+use std::os::unix::net::UnixStream;
 
-const SCREENSHOT_PATH: &str = "/tmp/screenshot.png";
+use swiftmouse::overlay;
 
-fn read_boxes(stdin: &mut std::io::Stdin) -> Vec<(u32, u32, u32, u32)> {
-    let boxes_len = stdin.read_u32(Endian::Little).unwrap();
-    let mut boxes = Vec::new();
-    for _ in 0..boxes_len {
-        let x = stdin.read_u32(Endian::Little).unwrap();
-        let y = stdin.read_u32(Endian::Little).unwrap();
-        let width = stdin.read_u32(Endian::Little).unwrap();
-        let height = stdin.read_u32(Endian::Little).unwrap();
-        boxes.push((x, y, width, height));
+// a show message starts with the id of the daemon's trace, the screenshot is the memfd sent with it
+fn read_show(message: &mut overlay::Message) -> std::io::Result<swiftmouse::gui::Show> {
+    let received_us = swiftmouse::trace::now_us();
+    let (trace, width, height) = overlay::read_show(&mut message.payload)?;
+    let fd = message.fd.take().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "show without a frame"))?;
+    let screenshot = swiftmouse::screenshot::read_shared_frame(std::fs::File::from(fd), width, height)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+    Ok(swiftmouse::gui::Show { trace, received_us, loaded_us: swiftmouse::trace::now_us(), screenshot })
+}
+
+fn read_command(message: &mut overlay::Message) -> std::io::Result<Option<swiftmouse::gui::Command>> {
+    use swiftmouse::gui::{Batch, Command};
+    let command = match message.tag {
+        overlay::SHOW_ELEMENTS => Command::ShowElements(read_show(message)?),
+        overlay::SHOW_WINDOWS => {
+            let show = read_show(message)?;
+            Command::ShowWindows(show, overlay::read_windows(&mut message.payload)?)
+        }
+        overlay::HIDE => Command::Hide,
+        overlay::BATCH_BIG_BOXES => Command::Batch(Batch::BigBoxes(overlay::read_boxes(&mut message.payload)?)),
+        overlay::BATCH_LINES => Command::Batch(Batch::Lines(overlay::read_boxes(&mut message.payload)?)),
+        overlay::BATCH_SMALL_IMAGES => Command::Batch(Batch::SmallImages(overlay::read_boxes(&mut message.payload)?)),
+        overlay::BATCH_MEDIA => Command::Batch(Batch::Media(overlay::read_boxes(&mut message.payload)?)),
+        overlay::BATCH_LINKS => Command::Batch(Batch::Links(overlay::read_boxes(&mut message.payload)?)),
+        overlay::BATCH_MEDIA_POINTS => Command::Batch(Batch::MediaPoints(overlay::read_boxes(&mut message.payload)?)),
+        overlay::BATCH_PINNED => Command::Batch(Batch::Pinned(overlay::read_labelled_boxes(&mut message.payload)?)),
+        overlay::BATCH_DONE => Command::Done,
+        tag => {
+            log::warn!("Unknown message {:?}", tag);
+            return Ok(None);
+        }
+    };
+    Ok(Some(command))
+}
+
+// Reads the daemon's messages until it exits, see Overlay in the daemon. A message that cannot
+// be read is skipped, the next one starts after it.
+fn read_commands(mut stream: UnixStream, tx: std::sync::mpsc::Sender<swiftmouse::gui::Command>) {
+    loop {
+        let mut message = match overlay::read_message(&mut stream) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        let command = match read_command(&mut message) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(err) => {
+                log::warn!("Failed to read message {:?}: {}", message.tag, err);
+                continue;
+            }
+        };
+        if tx.send(command).is_err() {
+            return;
+        }
     }
-    boxes
 }
 
+// Stays running between invocations, the daemon shows and hides it. Its stdin is a socket to the
+// daemon, events are written back to it.
 #[tokio::main]
 pub async fn main() {
-    let mut stdin = std::io::stdin();
-    let big_boxes = read_boxes(&mut stdin);
-    let line_boxes = read_boxes(&mut stdin);
-    let small_images = read_boxes(&mut stdin);
-    let large_images = read_boxes(&mut stdin);
-    let links = read_boxes(&mut stdin);
-    
+    swiftmouse::logging::init();
+    swiftmouse::logging::configure(&swiftmouse::config::load().logging);
+    // stdin is read as the socket, not through the buffered Stdin, so the memfds arrive with
+    // their messages
+    let stream = UnixStream::from(std::io::stdin().as_fd().try_clone_to_owned().unwrap());
+    let events = stream.try_clone().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || read_commands(stream, tx));
+
     // start autoclick session
     swiftmouse::autotype::start_autoclick_session().await.unwrap();
-    // screen width and height
-    swiftmouse::gui::show_gui( big_boxes, line_boxes, small_images, large_images, links, SCREENSHOT_PATH.to_string());
-
-}
\ No newline at end of file
+    swiftmouse::gui::show_overlay(rx, events);
+}
@@ -0,0 +1,46 @@
+// Native messaging host for the swiftmouse browser extension. The browser starts it and writes
+// length prefixed JSON messages to stdin, each holding the clickable elements of the current
+// viewport. They are converted to screen coordinates and pushed to the daemon over D-Bus.
+
+use swiftmouse::browser;
+use zbus::blocking::{Connection, Proxy};
+
+// returns how many elements the daemon stored, an error if it rejected them
+fn push(connection: &Connection, message: &browser::ElementsMessage) -> Result<u32, Box<dyn std::error::Error>> {
+    let elements = serde_json::to_string(&message.to_screen())?;
+    let proxy = Proxy::new(connection, "com.quexten.swiftmouse", "/com/quexten/swiftmouse", "com.quexten.swiftmouse")?;
+    let stored: u32 = proxy.call("PushBrowserElements", &(elements,))?;
+    Ok(stored)
+}
+
+fn run<R: std::io::Read, W: std::io::Write>(input: &mut R, output: &mut W, connection: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+    while let Some(message) = browser::read_message(input)? {
+        let reply = match serde_json::from_str::<browser::ElementsMessage>(&message) {
+            Ok(message) => match push(connection, &message) {
+                Ok(stored) => serde_json::json!({ "ok": true, "elements": stored }),
+                Err(err) => serde_json::json!({ "ok": false, "error": err.to_string() }),
+            },
+            Err(err) => serde_json::json!({ "ok": false, "error": err.to_string() }),
+        };
+        browser::write_message(output, &reply.to_string())?;
+    }
+    Ok(())
+}
+
+fn main() {
+    // stdout belongs to the browser, the log goes to stderr
+    swiftmouse::logging::init();
+    let connection = match Connection::session() {
+        Ok(connection) => connection,
+        Err(err) => {
+            log::error!("Failed to connect to the session bus: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut stdin = std::io::stdin().lock();
+    let mut stdout = std::io::stdout().lock();
+    if let Err(err) = run(&mut stdin, &mut stdout, &connection) {
+        log::error!("{}", err);
+        std::process::exit(1);
+    }
+}
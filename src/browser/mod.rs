@@ -0,0 +1,114 @@
+use std::io::{Read, Write};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+type Received = Option<(std::time::Instant, Vec<BrowserElement>)>;
+
+// latest elements pushed by the browser extension, with the time they arrived
+static BROWSER_ELEMENTS: Lazy<std::sync::Mutex<Received>> = Lazy::new(|| std::sync::Mutex::new(None));
+
+// native messaging caps messages from the extension at 64 MiB
+const MAX_MESSAGE_LEN: u32 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+pub struct Offset {
+    pub x: f64,
+    pub y: f64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ElementRect {
+    // "link", "button", "input", ...
+    pub kind: String,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+// What the extension sends: element rects relative to the viewport, where the window is on
+// screen, where the viewport is inside the window (tabs, toolbars) and the device pixel ratio.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ElementsMessage {
+    pub window: Offset,
+    #[serde(default)]
+    pub viewport: Offset,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    pub elements: Vec<ElementRect>,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+// An element in screen pixels, as sent from the host to the daemon
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BrowserElement {
+    pub link: bool,
+    pub min_x: usize,
+    pub min_y: usize,
+    pub max_x: usize,
+    pub max_y: usize,
+}
+
+impl ElementsMessage {
+    pub fn to_screen(&self) -> Vec<BrowserElement> {
+        self.elements.iter().filter(|element| element.width > 0.0 && element.height > 0.0).filter_map(|element| {
+            let min_x = (self.window.x + self.viewport.x + element.x) * self.scale;
+            let min_y = (self.window.y + self.viewport.y + element.y) * self.scale;
+            if min_x < 0.0 || min_y < 0.0 {
+                return None;
+            }
+            Some(BrowserElement {
+                link: element.kind == "link",
+                min_x: min_x as usize,
+                min_y: min_y as usize,
+                max_x: (min_x + element.width * self.scale) as usize,
+                max_y: (min_y + element.height * self.scale) as usize,
+            })
+        }).collect::<Vec<BrowserElement>>()
+    }
+}
+
+// Native messaging framing: a u32 length in native byte order followed by that much JSON.
+// Returns None when the browser closed the pipe.
+pub fn read_message<R: Read>(input: &mut R) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut len = [0u8; 4];
+    match input.read_exact(&mut len) {
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(Box::new(err)),
+    }
+    let len = u32::from_ne_bytes(len);
+    if len > MAX_MESSAGE_LEN {
+        return Err(format!("message too long: {}", len).into());
+    }
+    let mut message = vec![0u8; len as usize];
+    input.read_exact(&mut message)?;
+    Ok(Some(String::from_utf8(message)?))
+}
+
+pub fn write_message<W: Write>(output: &mut W, message: &str) -> Result<(), Box<dyn std::error::Error>> {
+    output.write_all(&(message.len() as u32).to_ne_bytes())?;
+    output.write_all(message.as_bytes())?;
+    output.flush()?;
+    Ok(())
+}
+
+// Called by the daemon when the host pushes elements (a JSON array of BrowserElement)
+pub fn store_json(json: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    let elements: Vec<BrowserElement> = serde_json::from_str(json)?;
+    let len = elements.len();
+    BROWSER_ELEMENTS.lock().unwrap().replace((std::time::Instant::now(), elements));
+    Ok(len)
+}
+
+// the stored elements, if they are younger than max_age
+pub fn latest(max_age: std::time::Duration) -> Vec<BrowserElement> {
+    match BROWSER_ELEMENTS.lock().unwrap().as_ref() {
+        Some((received, elements)) if received.elapsed() <= max_age => elements.clone(),
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+const CONFIG_FILE: &str = "swiftmouse/config.toml";
+const TEMPLATES_DIR: &str = "swiftmouse/templates";
+const CLASSIFIER_MODEL: &str = "swiftmouse/classifier.onnx";
+const LABELS_FILE: &str = "swiftmouse/labels.json";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub grouping: GroupingConfig,
+    pub text: TextConfig,
+    pub lines: LineConfig,
+    pub media: MediaConfig,
+    pub detectors: DetectorConfig,
+    pub templates: TemplateConfig,
+    pub accessibility: AccessibilityConfig,
+    pub browser: BrowserConfig,
+    pub classifier: ClassifierConfig,
+    pub cursor: CursorConfig,
+    pub settle: SettleConfig,
+    pub labels: LabelConfig,
+    pub cache: CacheConfig,
+    pub prefetch: PrefetchConfig,
+    pub logging: LoggingConfig,
+}
+
+// Morphology applied to the edge map before glyphs are labelled into boxes.
+// Kernel sizes are in downsampled pixels, 0 or 1 disables the operation.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GroupingConfig {
+    pub dilate_x: usize,
+    pub dilate_y: usize,
+    pub close_x: usize,
+    pub close_y: usize,
+    // estimate the closing kernels from the measured glyph spacing, overrides close_x / close_y
+    pub auto: bool,
+}
+
+impl Default for GroupingConfig {
+    fn default() -> Self {
+        GroupingConfig {
+            dilate_x: 0,
+            dilate_y: 0,
+            close_x: 0,
+            close_y: 0,
+            auto: false,
+        }
+    }
+}
+
+// Components up to height_ratio times the most common glyph height on the page count as text,
+// everything taller is a box. fallback_max_height is used when there are too few glyphs to
+// estimate from.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TextConfig {
+    pub height_ratio: f32,
+    pub min_samples: usize,
+    pub fallback_max_height: usize,
+}
+
+impl Default for TextConfig {
+    fn default() -> Self {
+        TextConfig {
+            height_ratio: 3.0,
+            min_samples: 20,
+            fallback_max_height: 15,
+        }
+    }
+}
+
+// Glyphs join a line when the horizontal gap is at most gap_ratio times the glyph height.
+// A gap that no text crosses within gutter_rows line heights above and below is a column
+// gutter and never merged across.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LineConfig {
+    pub gap_ratio: f32,
+    pub gutter_rows: f32,
+}
+
+impl Default for LineConfig {
+    fn default() -> Self {
+        LineConfig {
+            gap_ratio: 1.0,
+            gutter_rows: 3.0,
+        }
+    }
+}
+
+// Large components are media (videos, canvases, maps, photos) when no single colour dominates
+// them and they are colourful or dense with edges, or when they change between two frames.
+// Sizes are in downsampled pixels.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MediaConfig {
+    pub min_width: usize,
+    pub min_height: usize,
+    pub max_dominant_colour: f32,
+    pub min_colour_stddev: f32,
+    pub min_edge_density: f32,
+    // grab a second frame and treat regions that change as media
+    pub motion: bool,
+    pub min_motion: f32,
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        MediaConfig {
+            min_width: 150,
+            min_height: 100,
+            max_dominant_colour: 0.4,
+            min_colour_stddev: 24.0,
+            min_edge_density: 0.3,
+            motion: false,
+            min_motion: 0.02,
+        }
+    }
+}
+
+// Detectors listed in order run first, in that order, the rest keep their default order
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DetectorConfig {
+    pub order: Vec<String>,
+    pub disabled: Vec<String>,
+}
+
+// Reference icons (PNG) matched on screen. Each match gets a fixed hint label, taken from
+// labels (keyed by file name) or else the file stem, so rr.png is always hinted as rr.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TemplateConfig {
+    pub dir: Option<std::path::PathBuf>,
+    pub threshold: f32,
+    // allowed ratio between the size on screen and the size of the png
+    pub min_scale: f32,
+    pub max_scale: f32,
+    // components up to this many times the area of a template are searched for it at its own
+    // size, e.g. toolbars the icon was grouped into
+    pub scan_area: f32,
+    pub labels: HashMap<String, String>,
+}
+
+impl Default for TemplateConfig {
+    fn default() -> Self {
+        TemplateConfig {
+            dir: None,
+            threshold: 0.8,
+            min_scale: 0.5,
+            max_scale: 2.5,
+            scan_area: 100.0,
+            labels: HashMap::new(),
+        }
+    }
+}
+
+// Element source backed by the AT-SPI accessibility tree. scale converts the logical
+// coordinates reported by applications to screenshot pixels.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AccessibilityConfig {
+    pub enabled: bool,
+    pub scale: f32,
+    pub budget_ms: u64,
+    pub max_nodes: usize,
+}
+
+impl Default for AccessibilityConfig {
+    fn default() -> Self {
+        AccessibilityConfig {
+            enabled: false,
+            scale: 1.0,
+            budget_ms: 150,
+            max_nodes: 5000,
+        }
+    }
+}
+
+// Elements pushed by the browser extension are used for max_age_ms after they arrive
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BrowserConfig {
+    pub max_age_ms: u64,
+}
+
+impl Default for BrowserConfig {
+    fn default() -> Self {
+        BrowserConfig {
+            max_age_ms: 2000,
+        }
+    }
+}
+
+// ONNX model that re-classifies the boxes found by the rules, needs the classifier feature.
+// The model takes a 1x3xSxS RGB crop in 0..1 and returns one score per entry of classes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClassifierConfig {
+    pub model: Option<std::path::PathBuf>,
+    pub input_size: usize,
+    // "box", "text", "image", "link", anything else keeps the kind found by the rules
+    pub classes: Vec<String>,
+    pub min_confidence: f32,
+}
+
+impl Default for ClassifierConfig {
+    fn default() -> Self {
+        ClassifierConfig {
+            model: None,
+            input_size: 32,
+            classes: vec!["box".to_string(), "text".to_string(), "image".to_string(), "link".to_string()],
+            min_confidence: 0.6,
+        }
+    }
+}
+
+// Hovers elements on X11 and reads the cursor shape to confirm links and find text fields.
+// Probing stops after budget_ms, each probe waits settle_ms for the application to react.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CursorConfig {
+    pub enabled: bool,
+    pub budget_ms: u64,
+    pub settle_ms: u64,
+}
+
+impl Default for CursorConfig {
+    fn default() -> Self {
+        CursorConfig {
+            enabled: false,
+            budget_ms: 200,
+            settle_ms: 8,
+        }
+    }
+}
+
+// Waits for the screen to stop changing before detecting. Frames are compared every
+// interval_ms, the screen is settled once less than threshold of the pixels changed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SettleConfig {
+    pub enabled: bool,
+    pub threshold: f32,
+    pub interval_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for SettleConfig {
+    fn default() -> Self {
+        SettleConfig {
+            enabled: false,
+            threshold: 0.002,
+            interval_ms: 16,
+            max_delay_ms: 300,
+        }
+    }
+}
+
+// Keeps the hint label of an element across invocations. Elements are matched by position, size
+// and appearance, per application, and forgotten after ttl_secs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LabelConfig {
+    pub stable: bool,
+    pub ttl_secs: u64,
+    // how far an element may move, relative to the screen diagonal
+    pub max_distance: f32,
+    // how many of the 64 appearance hash bits may differ
+    pub max_hash_distance: u32,
+}
+
+impl Default for LabelConfig {
+    fn default() -> Self {
+        LabelConfig {
+            stable: true,
+            ttl_secs: 7 * 24 * 60 * 60,
+            max_distance: 0.05,
+            max_hash_distance: 10,
+        }
+    }
+}
+
+// Reuses the last detection result for the parts of the screen that did not change, compared
+// in tiles of tile_size pixels. Only the changed tiles are detected again.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub tile_size: usize,
+    // changed tiles are detected again with this many pixels around them
+    pub margin: usize,
+    // above this share of the screen changed, the whole frame is detected again
+    pub max_dirty: f32,
+    // cached elements up to this share of the screen are detected again whole when a changed
+    // tile touches them, larger ones (windows, panels) are kept
+    pub max_grow: f32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: true,
+            tile_size: 64,
+            margin: 32,
+            max_dirty: 0.5,
+            max_grow: 0.05,
+        }
+    }
+}
+
+// Detects in the background whenever the screen changed, so the hints are ready when the
+// shortcut is pressed. The screen is checked every interval_ms, and at most cpu_budget of the
+// time is spent detecting. Needs the cache.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PrefetchConfig {
+    pub enabled: bool,
+    pub interval_ms: u64,
+    pub cpu_budget: f32,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        PrefetchConfig {
+            enabled: false,
+            interval_ms: 250,
+            cpu_budget: 0.2,
+        }
+    }
+}
+
+// Log levels per module in RUST_LOG syntax, e.g. "warn,swiftmouse::screenshot=trace". The
+// SWIFTMOUSE_LOG environment variable overrides it. journald sends the log to the systemd
+// journal instead of stderr.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub level: String,
+    pub journald: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "warn".to_string(),
+            journald: false,
+        }
+    }
+}
+
+fn config_dir() -> Option<std::path::PathBuf> {
+    match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => Some(std::path::PathBuf::from(dir)),
+        _ => match std::env::var("HOME") {
+            Ok(home) => Some(std::path::Path::new(&home).join(".config")),
+            Err(_) => None,
+        },
+    }
+}
+
+pub fn templates_dir() -> Option<std::path::PathBuf> {
+    config_dir().map(|dir| dir.join(TEMPLATES_DIR))
+}
+
+pub fn classifier_model_path() -> Option<std::path::PathBuf> {
+    config_dir().map(|dir| dir.join(CLASSIFIER_MODEL))
+}
+
+fn state_dir() -> Option<std::path::PathBuf> {
+    match std::env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => Some(std::path::PathBuf::from(dir)),
+        _ => match std::env::var("HOME") {
+            Ok(home) => Some(std::path::Path::new(&home).join(".local/state")),
+            Err(_) => None,
+        },
+    }
+}
+
+pub fn labels_path() -> Option<std::path::PathBuf> {
+    state_dir().map(|dir| dir.join(LABELS_FILE))
+}
+
+pub fn config_path() -> Option<std::path::PathBuf> {
+    config_dir().map(|dir| dir.join(CONFIG_FILE))
+}
+
+// Reads the config file, missing files or keys fall back to the defaults
+pub fn load() -> Config {
+    let path = match config_path() {
+        Some(path) => path,
+        None => return Config::default(),
+    };
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(_) => {
+            log::info!("No config at {:?}, using defaults", path);
+            return Config::default();
+        }
+    };
+    match toml::from_str(&contents) {
+        Ok(config) => {
+            log::debug!("Loaded {:?}", path);
+            config
+        }
+        Err(err) => {
+            log::warn!("Failed to parse {:?}: {}", path, err);
+            Config::default()
+        }
+    }
+}
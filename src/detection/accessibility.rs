@@ -0,0 +1,99 @@
+use rayon::iter::IntoParallelRefIterator;
+use rayon::iter::ParallelIterator;
+
+use crate::accessibility;
+use crate::config;
+
+use super::{DetectionContext, Detector, Element, ElementKind, Rect};
+
+// Buttons, links and fields reported by AT-SPI. Their bounds are exact, so they replace the
+// vision based elements they overlap.
+pub struct AccessibilityDetector {
+    connection: Option<zbus::blocking::Connection>,
+    config: config::AccessibilityConfig,
+}
+
+impl AccessibilityDetector {
+    pub fn connect(accessibility_config: &config::AccessibilityConfig) -> AccessibilityDetector {
+        let connection = match accessibility::connect() {
+            Ok(connection) => Some(connection),
+            Err(err) => {
+                log::warn!("Failed to connect to the accessibility bus: {}", err);
+                None
+            }
+        };
+        AccessibilityDetector { connection, config: accessibility_config.clone() }
+    }
+
+    // screen pixels, not clipped to the screen
+    fn to_rect(&self, (x, y, width, height): (i32, i32, i32, i32)) -> Option<Rect> {
+        let scale = self.config.scale;
+        let min_x = (x as f32 * scale).max(0.0) as usize;
+        let min_y = (y as f32 * scale).max(0.0) as usize;
+        let max_x = ((x + width) as f32 * scale).max(0.0) as usize;
+        let max_y = ((y + height) as f32 * scale).max(0.0) as usize;
+        if width <= 0 || height <= 0 || min_x >= max_x || min_y >= max_y {
+            return None;
+        }
+        Some((min_x, min_y, max_x, max_y))
+    }
+}
+
+impl Detector for AccessibilityDetector {
+    fn name(&self) -> &'static str {
+        "accessibility"
+    }
+
+    fn supersedes(&self) -> bool {
+        true
+    }
+
+    // the toolkit knows its own widgets better than the page scripts of a browser
+    fn precedence(&self) -> u32 {
+        2
+    }
+
+    fn whole_screen(&self) -> bool {
+        true
+    }
+
+    fn from_pixels(&self) -> bool {
+        false
+    }
+
+    fn foreground_only(&self) -> bool {
+        true
+    }
+
+    fn detect(&self, context: &DetectionContext, _elements: &[Element]) -> Vec<Element> {
+        let connection = match &self.connection {
+            Some(connection) => connection,
+            None => return Vec::new(),
+        };
+        // only the focused window is walked, STATE_SHOWING is also set on the widgets of windows
+        // hidden under it
+        let windows = match accessibility::focused_windows(connection) {
+            Ok(Some((name, windows))) => {
+                log::debug!("Focused application {:?}", name);
+                windows
+            }
+            Ok(None) => return Vec::new(),
+            Err(err) => {
+                log::warn!("Failed to find the focused window: {}", err);
+                return Vec::new();
+            }
+        };
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(self.config.budget_ms);
+        windows.par_iter().map(|window| {
+            accessibility::collect_actionable(connection, window, deadline, self.config.max_nodes)
+        }).collect::<Vec<Vec<accessibility::AccessibleElement>>>().concat().into_iter().filter_map(|element| {
+            let kind = match element.role {
+                accessibility::ROLE_LINK => ElementKind::Link,
+                accessibility::ROLE_ENTRY | accessibility::ROLE_TEXT | accessibility::ROLE_PASSWORD_TEXT => ElementKind::Field,
+                _ => ElementKind::BigBox,
+            };
+            self.to_rect(element.extents).and_then(|rect| context.from_screen(rect)).map(|rect| Element::new(kind, rect))
+        }).collect::<Vec<Element>>()
+    }
+}
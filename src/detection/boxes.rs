@@ -0,0 +1,52 @@
+use super::{of_kind, DetectionContext, Detector, Element, ElementKind};
+
+// Components that are not text: buttons, fields, icons, panels
+pub struct BigBoxDetector;
+
+impl Detector for BigBoxDetector {
+    fn name(&self) -> &'static str {
+        "boxes"
+    }
+
+    fn detect(&self, context: &DetectionContext, _elements: &[Element]) -> Vec<Element> {
+        context.components.iter().filter(|component| {
+            !context.is_text(component)
+        }).map(|component| {
+            Element::new(ElementKind::BigBox, context.unmap(component))
+        }).collect::<Vec<Element>>()
+    }
+}
+
+// small images are big boxes that are > 50% white
+pub struct SmallImageDetector;
+
+impl Detector for SmallImageDetector {
+    fn name(&self) -> &'static str {
+        "images"
+    }
+
+    fn detect(&self, context: &DetectionContext, elements: &[Element]) -> Vec<Element> {
+        let map = &context.edge_map;
+        let mut small_images = Vec::new();
+        for big_box in of_kind(elements, ElementKind::BigBox) {
+            let (min_x, min_y, max_x, max_y) = context.downsample(&big_box);
+            let mut white = 0;
+            let mut total = 0;
+            for x in min_x..max_x {
+                for y in min_y..max_y {
+                    if x >= map.len() as usize || y >= map[0].len() as usize {
+                        continue;
+                    }
+                    total += 1;
+                    if map[x][y] {
+                        white += 1;
+                    }
+                }
+            }
+            if white as f32 / total as f32 > 0.5 {
+                small_images.push(Element::new(ElementKind::SmallImage, big_box));
+            }
+        }
+        small_images
+    }
+}
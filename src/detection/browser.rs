@@ -0,0 +1,45 @@
+use crate::browser;
+use crate::config;
+
+use super::{DetectionContext, Detector, Element, ElementKind};
+
+// Links and controls pushed by the browser extension through the native messaging host. The
+// browser knows the exact rects, so they replace the vision based elements they overlap.
+pub struct BrowserDetector {
+    config: config::BrowserConfig,
+}
+
+impl BrowserDetector {
+    pub fn new(browser_config: &config::BrowserConfig) -> BrowserDetector {
+        BrowserDetector { config: browser_config.clone() }
+    }
+}
+
+impl Detector for BrowserDetector {
+    fn name(&self) -> &'static str {
+        "browser"
+    }
+
+    fn supersedes(&self) -> bool {
+        true
+    }
+
+    fn precedence(&self) -> u32 {
+        1
+    }
+
+    fn whole_screen(&self) -> bool {
+        true
+    }
+
+    fn from_pixels(&self) -> bool {
+        false
+    }
+
+    fn detect(&self, context: &DetectionContext, _elements: &[Element]) -> Vec<Element> {
+        browser::latest(std::time::Duration::from_millis(self.config.max_age_ms)).into_iter().filter_map(|element| {
+            let kind = if element.link { ElementKind::Link } else { ElementKind::BigBox };
+            context.from_screen((element.min_x, element.min_y, element.max_x, element.max_y)).map(|rect| Element::new(kind, rect))
+        }).collect::<Vec<Element>>()
+    }
+}
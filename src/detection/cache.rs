@@ -0,0 +1,269 @@
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use super::{Element, Rect, Screenshot};
+
+// What the cache knows about a new frame
+pub enum Lookup {
+    // The frame is identical to the cached one. The elements are those found on its pixels, the
+    // detectors with other sources run again on top of them with the components.
+    Unchanged { elements: Vec<Element>, components: Vec<Rect>, frame: FrameParams },
+    // Some tiles changed. The elements of the detectors that run on regions and the components
+    // are those of the cached frame, see dirty_regions for which of them are still valid.
+    Partial { dirty: Vec<Rect>, regional: Vec<Element>, components: Vec<Rect>, frame: FrameParams },
+    Miss,
+}
+
+// What a region of the frame needs to be detected like the full frame
+#[derive(Debug, Clone, Copy)]
+pub struct FrameParams {
+    pub block: usize,
+    pub stride: usize,
+    pub body_text_height: usize,
+    pub max_text_height: usize,
+}
+
+// The detection result of the last frame, with a hash of every tile of it
+pub struct FrameCache {
+    tile_size: usize,
+    dimensions: (u32, u32),
+    hashes: Vec<u64>,
+    // only the elements found on the pixels, see Detector::from_pixels. Browser, accessibility
+    // and cursor elements change without the screen changing, so they are never cached.
+    elements: Vec<Element>,
+    // the elements before the whole-screen detectors ran, and the components in downsampled
+    // coordinates, to detect changed regions again
+    regional: Vec<Element>,
+    components: Vec<Rect>,
+    frame: Option<FrameParams>,
+}
+
+// FNV-1a over the pixels of every tile, row major by tile
+pub fn tile_hashes(screenshot: &Screenshot, tile_size: usize) -> Vec<u64> {
+    let width = screenshot.width() as usize;
+    let height = screenshot.height() as usize;
+    let tiles_x = (width + tile_size - 1) / tile_size;
+    let tiles_y = (height + tile_size - 1) / tile_size;
+    let raw = screenshot.as_raw();
+    (0..tiles_x * tiles_y).into_par_iter().map(|tile| {
+        let min_x = (tile % tiles_x) * tile_size;
+        let min_y = (tile / tiles_x) * tile_size;
+        let max_x = (min_x + tile_size).min(width);
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for y in min_y..(min_y + tile_size).min(height) {
+            for byte in &raw[(y * width + min_x) * 3..(y * width + max_x) * 3] {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        hash
+    }).collect::<Vec<u64>>()
+}
+
+pub fn intersects(a: &Rect, b: &Rect) -> bool {
+    a.0 <= b.2 && b.0 <= a.2 && a.1 <= b.3 && b.1 <= a.3
+}
+
+impl FrameCache {
+    pub fn new(tile_size: usize) -> Self {
+        FrameCache {
+            tile_size: tile_size.max(1),
+            dimensions: (0, 0),
+            hashes: Vec::new(),
+            elements: Vec::new(),
+            regional: Vec::new(),
+            components: Vec::new(),
+            frame: None,
+        }
+    }
+
+    pub fn tile_size(&self) -> usize {
+        self.tile_size
+    }
+
+    pub fn lookup(&self, dimensions: (u32, u32), hashes: &[u64]) -> Lookup {
+        if self.hashes.is_empty() || dimensions != self.dimensions || hashes.len() != self.hashes.len() {
+            return Lookup::Miss;
+        }
+        let tiles_x = (dimensions.0 as usize + self.tile_size - 1) / self.tile_size;
+        let dirty = (0..hashes.len()).filter(|tile| hashes[*tile] != self.hashes[*tile]).map(|tile| {
+            let min_x = (tile % tiles_x) * self.tile_size;
+            let min_y = (tile / tiles_x) * self.tile_size;
+            (
+                min_x,
+                min_y,
+                (min_x + self.tile_size).min(dimensions.0 as usize) - 1,
+                (min_y + self.tile_size).min(dimensions.1 as usize) - 1,
+            )
+        }).collect::<Vec<Rect>>();
+        let frame = match self.frame {
+            Some(frame) => frame,
+            None => return Lookup::Miss,
+        };
+        if dirty.is_empty() {
+            return Lookup::Unchanged { elements: self.elements.clone(), components: self.components.clone(), frame };
+        }
+        Lookup::Partial { dirty, regional: self.regional.clone(), components: self.components.clone(), frame }
+    }
+
+    // elements is what the detectors found on the pixels, regional and components what the
+    // whole-screen detectors started from
+    pub fn store(&mut self, dimensions: (u32, u32), hashes: Vec<u64>, elements: Vec<Element>, regional: Vec<Element>, components: Vec<Rect>, frame: FrameParams) {
+        self.dimensions = dimensions;
+        self.hashes = hashes;
+        self.elements = elements;
+        self.regional = regional;
+        self.components = components;
+        self.frame = Some(frame);
+    }
+}
+
+fn contains(outer: &Rect, inner: &Rect) -> bool {
+    outer.0 <= inner.0 && outer.1 <= inner.1 && inner.2 <= outer.2 && inner.3 <= outer.3
+}
+
+fn area(rect: &Rect) -> usize {
+    (rect.2 - rect.0 + 1) * (rect.3 - rect.1 + 1)
+}
+
+// A cached rect is still valid if it does not touch any of the regions, or if it is a container
+// larger than max_area that no region contains, see dirty_regions
+fn still_valid(rect: &Rect, regions: &[Rect], max_area: usize) -> bool {
+    let large = area(rect) > max_area;
+    !regions.iter().any(|region| intersects(rect, region) && (!large || contains(region, rect)))
+}
+
+// the elements that are still valid after the regions are detected again
+pub fn outside(elements: &[Element], regions: &[Rect], max_area: usize) -> Vec<Element> {
+    elements.iter().filter(|element| still_valid(&element.rect, regions, max_area)).cloned().collect::<Vec<Element>>()
+}
+
+// the components (downsampled by scale) that are still valid after the regions are detected again
+pub fn components_outside(components: &[Rect], regions: &[Rect], scale: usize, max_area: usize) -> Vec<Rect> {
+    components.iter().filter(|component| {
+        let rect = (component.0 * scale, component.1 * scale, component.2 * scale + scale - 1, component.3 * scale + scale - 1);
+        still_valid(&rect, regions, max_area)
+    }).cloned().collect::<Vec<Rect>>()
+}
+
+// Groups the dirty tiles into regions to detect again. Each region grows by margin and to the
+// cached elements up to max_area it touches, so elements crossing its border are detected again
+// whole, touching regions are merged, and the origin is aligned to scale so the region is
+// downsampled on the same grid as the full frame. Larger elements (windows, panels, videos) are
+// not grown over, a caret blinking inside a window would otherwise detect the whole window
+// again. They are kept as cached unless a region contains them. The cached elements outside of
+// the regions are still valid.
+pub fn dirty_regions(dirty: &[Rect], elements: &[Element], margin: usize, scale: usize, dimensions: (u32, u32), max_area: usize) -> Vec<Rect> {
+    let max_x = dimensions.0 as usize - 1;
+    let max_y = dimensions.1 as usize - 1;
+    let align = |region: Rect| (region.0 / scale * scale, region.1 / scale * scale, region.2.min(max_x), region.3.min(max_y));
+    let mut regions = dirty.iter().map(|tile| {
+        align((tile.0.saturating_sub(margin), tile.1.saturating_sub(margin), tile.2 + margin, tile.3 + margin))
+    }).collect::<Vec<Rect>>();
+    let elements = elements.iter().map(|element| {
+        (element.rect.0, element.rect.1, element.rect.2.min(max_x), element.rect.3.min(max_y))
+    }).filter(|rect| area(rect) <= max_area).collect::<Vec<Rect>>();
+
+    // grow and merge until no region touches another one or an element it does not contain
+    let mut merged = true;
+    while merged {
+        merged = false;
+        for region in regions.iter_mut() {
+            for rect in &elements {
+                if intersects(region, rect) && !contains(region, rect) {
+                    *region = align((region.0.min(rect.0), region.1.min(rect.1), region.2.max(rect.2), region.3.max(rect.3)));
+                    merged = true;
+                }
+            }
+        }
+        let mut index = 0;
+        while index < regions.len() {
+            let mut other = index + 1;
+            while other < regions.len() {
+                if intersects(&regions[index], &regions[other]) {
+                    let b = regions.remove(other);
+                    let a = regions[index];
+                    regions[index] = (a.0.min(b.0), a.1.min(b.1), a.2.max(b.2), a.3.max(b.3));
+                    merged = true;
+                } else {
+                    other += 1;
+                }
+            }
+            index += 1;
+        }
+    }
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detection::ElementKind;
+
+    #[test]
+    fn lookup() {
+        let frame = FrameParams { block: 1, stride: 1, body_text_height: 0, max_text_height: 0 };
+        let mut screenshot = Screenshot::new(8, 8);
+        let mut cache = FrameCache::new(4);
+        assert!(matches!(cache.lookup(screenshot.dimensions(), &tile_hashes(&screenshot, 4)), Lookup::Miss));
+
+        let elements = vec![Element::new(ElementKind::BigBox, (1, 1, 2, 2))];
+        cache.store(screenshot.dimensions(), tile_hashes(&screenshot, 4), elements.clone(), elements.clone(), vec![(1, 1, 2, 2)], frame);
+        match cache.lookup(screenshot.dimensions(), &tile_hashes(&screenshot, 4)) {
+            Lookup::Unchanged { elements: cached, components, .. } => {
+                assert_eq!(cached, elements);
+                assert_eq!(components, vec![(1, 1, 2, 2)]);
+            }
+            _ => panic!("expected the frame to be unchanged"),
+        }
+
+        screenshot.put_pixel(5, 6, image::Rgb([255, 0, 0]));
+        match cache.lookup(screenshot.dimensions(), &tile_hashes(&screenshot, 4)) {
+            Lookup::Partial { dirty, .. } => assert_eq!(dirty, vec![(4, 4, 7, 7)]),
+            _ => panic!("expected one changed tile"),
+        }
+        assert!(matches!(cache.lookup((8, 4), &tile_hashes(&screenshot, 4)), Lookup::Miss));
+    }
+
+    fn boxes(rects: &[Rect]) -> Vec<Element> {
+        rects.iter().map(|rect| Element::new(ElementKind::BigBox, *rect)).collect::<Vec<Element>>()
+    }
+
+    #[test]
+    fn regions_grow_over_the_elements_they_touch() {
+        let elements = boxes(&[(100, 100, 160, 110), (150, 105, 170, 140), (0, 0, 10, 10)]);
+        // the second box is reached through the first one
+        assert_eq!(dirty_regions(&[(64, 64, 127, 127)], &elements, 0, 1, (256, 256), 1000), vec![(64, 64, 170, 140)]);
+        // the origin is aligned to the scale
+        assert_eq!(dirty_regions(&[(64, 64, 127, 127)], &[], 3, 4, (256, 256), 1000), vec![(60, 60, 130, 130)]);
+    }
+
+    #[test]
+    fn touching_regions_are_merged() {
+        // next to each other, the margin makes them overlap
+        assert_eq!(dirty_regions(&[(0, 0, 63, 63), (64, 0, 127, 63)], &[], 8, 1, (256, 256), 1000), vec![(0, 0, 135, 71)]);
+        assert_eq!(dirty_regions(&[(0, 0, 63, 63), (192, 0, 255, 63)], &[], 8, 1, (256, 256), 1000), vec![(0, 0, 71, 71), (184, 0, 255, 71)]);
+        // an element bridges them
+        let bridge = boxes(&[(60, 10, 200, 20)]);
+        assert_eq!(dirty_regions(&[(0, 0, 63, 63), (192, 0, 255, 63)], &bridge, 8, 1, (256, 256), 10000), vec![(0, 0, 255, 71)]);
+    }
+
+    #[test]
+    fn regions_are_clamped_to_the_screen() {
+        let elements = boxes(&[(200, 250, 300, 300)]);
+        assert_eq!(dirty_regions(&[(192, 192, 255, 255)], &elements, 32, 1, (256, 256), 10000), vec![(160, 160, 255, 255)]);
+    }
+
+    #[test]
+    fn large_containers_are_not_grown_over() {
+        // a window around a blinking caret, and a button next to it
+        let elements = boxes(&[(0, 0, 255, 255), (70, 70, 80, 80), (200, 200, 210, 210)]);
+        let regions = dirty_regions(&[(64, 64, 127, 127)], &elements, 0, 1, (256, 256), 1000);
+        assert_eq!(regions, vec![(64, 64, 127, 127)]);
+        assert_eq!(outside(&elements, &regions, 1000), boxes(&[(0, 0, 255, 255), (200, 200, 210, 210)]));
+        // a region that contains the container detects it again
+        assert_eq!(outside(&elements, &[(0, 0, 255, 255)], 1000), Vec::new());
+        // without the bound the window is detected again whole
+        assert_eq!(dirty_regions(&[(64, 64, 127, 127)], &elements, 0, 1, (256, 256), usize::MAX), vec![(0, 0, 255, 255)]);
+        assert_eq!(components_outside(&[(0, 0, 63, 63), (17, 17, 20, 20)], &regions, 4, 1000), vec![(0, 0, 63, 63)]);
+    }
+}
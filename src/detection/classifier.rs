@@ -0,0 +1,108 @@
+use image::imageops::FilterType;
+use rayon::iter::IntoParallelRefIterator;
+use rayon::iter::ParallelIterator;
+use tract_onnx::prelude::*;
+
+use crate::config;
+
+use super::{DetectionContext, Detector, Element, ElementKind};
+
+type Model = SimplePlan<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>>;
+
+// Re-classifies the boxes, lines, images and links found by the rules with an ONNX model. Crops
+// the model is unsure about keep the kind the rules gave them.
+pub struct ClassifierDetector {
+    model: Model,
+    classes: Vec<Option<ElementKind>>,
+    config: config::ClassifierConfig,
+}
+
+fn class_kind(class: &str) -> Option<ElementKind> {
+    match class {
+        "box" => Some(ElementKind::BigBox),
+        "text" => Some(ElementKind::Line),
+        "image" => Some(ElementKind::SmallImage),
+        "link" => Some(ElementKind::Link),
+        _ => None,
+    }
+}
+
+impl ClassifierDetector {
+    // None if there is no model, then only the rules are used
+    pub fn load(classifier_config: &config::ClassifierConfig) -> Option<ClassifierDetector> {
+        let path = classifier_config.model.clone().or_else(config::classifier_model_path)?;
+        if !path.exists() {
+            log::info!("No model at {:?}, using the rules", path);
+            return None;
+        }
+        let size = classifier_config.input_size;
+        let model = tract_onnx::onnx()
+            .model_for_path(&path)
+            .and_then(|model| model.with_input_fact(0, f32::fact([1, 3, size, size]).into()))
+            .and_then(|model| model.into_optimized())
+            .and_then(|model| model.into_runnable());
+        match model {
+            Ok(model) => {
+                log::debug!("Loaded {:?}", path);
+                Some(ClassifierDetector {
+                    model,
+                    classes: classifier_config.classes.iter().map(|class| class_kind(class)).collect::<Vec<Option<ElementKind>>>(),
+                    config: classifier_config.clone(),
+                })
+            }
+            Err(err) => {
+                log::warn!("Failed to load {:?}: {}", path, err);
+                None
+            }
+        }
+    }
+
+    // the predicted kind and its softmax confidence
+    fn classify(&self, context: &DetectionContext, element: &Element) -> TractResult<Option<(ElementKind, f32)>> {
+        let (min_x, min_y, max_x, max_y) = element.rect;
+        let size = self.config.input_size;
+        let crop = image::imageops::crop_imm(context.screenshot, min_x as u32, min_y as u32, (max_x - min_x + 1) as u32, (max_y - min_y + 1) as u32);
+        let resized = image::imageops::resize(&*crop, size as u32, size as u32, FilterType::Triangle);
+        let input: Tensor = tract_ndarray::Array4::from_shape_fn((1, 3, size, size), |(_, channel, y, x)| {
+            resized.get_pixel(x as u32, y as u32).0[channel] as f32 / 255.0
+        }).into();
+
+        let outputs = self.model.run(tvec!(input.into()))?;
+        let scores = outputs[0].to_array_view::<f32>()?.iter().cloned().collect::<Vec<f32>>();
+        let max = scores.iter().cloned().fold(f32::MIN, f32::max);
+        let sum = scores.iter().map(|score| (score - max).exp()).sum::<f32>();
+        let best = scores.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1));
+        Ok(best.and_then(|(index, score)| {
+            let kind = (*self.classes.get(index)?)?;
+            Some((kind, (score - max).exp() / sum))
+        }))
+    }
+}
+
+impl Detector for ClassifierDetector {
+    fn name(&self) -> &'static str {
+        "classifier"
+    }
+
+    fn replaces(&self) -> bool {
+        true
+    }
+
+    fn detect(&self, context: &DetectionContext, elements: &[Element]) -> Vec<Element> {
+        elements.par_iter().map(|element| {
+            let classified = matches!(element.kind, ElementKind::BigBox | ElementKind::Line | ElementKind::SmallImage | ElementKind::Link);
+            let (min_x, min_y, max_x, max_y) = element.rect;
+            if !classified || max_x <= min_x || max_y <= min_y || context.cancelled() {
+                return element.clone();
+            }
+            match self.classify(context, element) {
+                Ok(Some((kind, confidence))) if confidence >= self.config.min_confidence => Element::new(kind, element.rect),
+                Ok(_) => element.clone(),
+                Err(err) => {
+                    log::warn!("Failed to classify {:?}: {}", element.rect, err);
+                    element.clone()
+                }
+            }
+        }).collect::<Vec<Element>>()
+    }
+}
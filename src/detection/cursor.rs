@@ -0,0 +1,135 @@
+use x11rb::connection::Connection;
+use x11rb::protocol::xfixes::ConnectionExt as XfixesConnectionExt;
+use x11rb::protocol::xproto::ConnectionExt;
+use x11rb::rust_connection::RustConnection;
+
+use crate::config;
+
+use super::{DetectionContext, Detector, Element, ElementKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CursorShape {
+    Hand,
+    Text,
+    Other,
+}
+
+// cursor theme names, including the hashes some themes use for the same cursors
+fn cursor_shape(name: &str) -> CursorShape {
+    match name {
+        "hand" | "hand1" | "hand2" | "pointer" | "pointing_hand" | "e29285e634086352946a0e7090d73106" | "9d800788f1b08800ae810202380a0822" => CursorShape::Hand,
+        "xterm" | "text" | "ibeam" | "vertical-text" => CursorShape::Text,
+        _ => CursorShape::Other,
+    }
+}
+
+// Verifies elements by hovering them and reading the cursor the application sets, through
+// XFixes. A hand confirms a link or button, an I-beam marks a text field. Only works on X11,
+// moves the real pointer and puts it back afterwards.
+pub struct CursorDetector {
+    conn: RustConnection,
+    root: u32,
+    config: config::CursorConfig,
+}
+
+impl CursorDetector {
+    pub fn connect(cursor_config: &config::CursorConfig) -> Option<CursorDetector> {
+        let (conn, screen_num) = match x11rb::connect(None) {
+            Ok(connection) => connection,
+            Err(err) => {
+                log::warn!("Failed to connect to the X server: {}", err);
+                return None;
+            }
+        };
+        let root = conn.setup().roots[screen_num].root;
+        // XFixes has to be told the version the client speaks before it answers requests
+        match conn.xfixes_query_version(4, 0).map(|cookie| cookie.reply()) {
+            Ok(Ok(_)) => {}
+            _ => {
+                log::warn!("XFixes is not available");
+                return None;
+            }
+        }
+        Some(CursorDetector { conn, root, config: cursor_config.clone() })
+    }
+
+    fn probe(&self, x: i16, y: i16) -> Result<CursorShape, Box<dyn std::error::Error>> {
+        self.conn.warp_pointer(x11rb::NONE, self.root, 0, 0, 0, 0, x, y)?;
+        self.conn.flush()?;
+        // give the application under the pointer time to change the cursor
+        std::thread::sleep(std::time::Duration::from_millis(self.config.settle_ms));
+        let cursor = self.conn.xfixes_get_cursor_image_and_name()?.reply()?;
+        Ok(cursor_shape(&String::from_utf8_lossy(&cursor.name)))
+    }
+}
+
+impl Detector for CursorDetector {
+    fn name(&self) -> &'static str {
+        "cursor"
+    }
+
+    fn replaces(&self) -> bool {
+        true
+    }
+
+    fn whole_screen(&self) -> bool {
+        true
+    }
+
+    fn from_pixels(&self) -> bool {
+        false
+    }
+
+    // it moves the user's pointer
+    fn foreground_only(&self) -> bool {
+        true
+    }
+
+    fn detect(&self, context: &DetectionContext, elements: &[Element]) -> Vec<Element> {
+        let start = std::time::Instant::now();
+        let budget = std::time::Duration::from_millis(self.config.budget_ms);
+        let pointer = match self.conn.query_pointer(self.root).map(|cookie| cookie.reply()) {
+            Ok(Ok(pointer)) => pointer,
+            _ => return elements.to_vec(),
+        };
+
+        // links are the least certain, then boxes, then lines that may be links
+        let mut candidates = (0..elements.len()).filter(|index| {
+            matches!(elements[*index].kind, ElementKind::Link | ElementKind::BigBox | ElementKind::Line)
+        }).collect::<Vec<usize>>();
+        candidates.sort_by_key(|index| match elements[*index].kind {
+            ElementKind::Link => 0,
+            ElementKind::BigBox => 1,
+            _ => 2,
+        });
+
+        let mut elements = elements.to_vec();
+        let mut probed = 0;
+        for index in candidates {
+            if start.elapsed() >= budget || context.cancelled() {
+                break;
+            }
+            let (min_x, min_y, max_x, max_y) = context.to_screen(elements[index].rect);
+            let shape = match self.probe(((min_x + max_x) / 2) as i16, ((min_y + max_y) / 2) as i16) {
+                Ok(shape) => shape,
+                Err(err) => {
+                    log::debug!("Probe failed: {}", err);
+                    break;
+                }
+            };
+            probed += 1;
+            let element = &mut elements[index];
+            element.kind = match (element.kind, shape) {
+                (ElementKind::Line, CursorShape::Hand) => ElementKind::Link,
+                (ElementKind::Link, CursorShape::Text) => ElementKind::Line,
+                (ElementKind::BigBox, CursorShape::Text) => ElementKind::Field,
+                (kind, _) => kind,
+            };
+        }
+
+        let _ = self.conn.warp_pointer(x11rb::NONE, self.root, 0, 0, 0, 0, pointer.root_x, pointer.root_y);
+        let _ = self.conn.flush();
+        log::debug!("Probed {:?} elements", probed);
+        elements
+    }
+}
@@ -0,0 +1,546 @@
+use std::cmp;
+
+use rayon::iter::IndexedParallelIterator;
+use rayon::iter::IntoParallelIterator;
+use rayon::iter::IntoParallelRefIterator;
+use rayon::iter::ParallelIterator;
+use rayon::slice::ParallelSliceMut;
+
+use crate::config;
+
+use super::CancellationToken;
+
+pub struct LumaPlane {
+    pub width: usize,
+    pub height: usize,
+    // r + g + b per pixel, row major
+    pub data: Vec<u16>,
+}
+
+impl LumaPlane {
+    pub fn get(&self, x: isize, y: isize) -> u16 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.data[y * self.width + x]
+    }
+}
+
+// Picks (luma block size, edge stride) for a frame of the given height. Text should end up
+// at about the size it has on a 1080p screen after the edge pass, so 4k frames are first
+// averaged down to 1080p and small screens skip the downsampling entirely.
+pub fn detection_scale(height: u32) -> (usize, usize) {
+    if height < 800 {
+        return (1, 1);
+    }
+    let block = ((height as f32 / 1080.0).round() as usize).max(1);
+    (block, 2)
+}
+
+// averages block x block pixels into one gray value, rows after a cancel are left black
+pub fn luma_plane(image: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, block: usize, cancel: &CancellationToken) -> LumaPlane {
+    let src_width = image.width() as usize;
+    let width = src_width / block;
+    let height = image.height() as usize / block;
+    // a region narrower than a block
+    if width == 0 {
+        return LumaPlane { width, height, data: Vec::new() };
+    }
+    let raw = image.as_raw();
+    let mut data = vec![0u16; width * height];
+    data.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        if cancel.is_cancelled() {
+            return;
+        }
+        for x in 0..width {
+            let mut sum: u32 = 0;
+            for dy in 0..block {
+                let offset = ((y * block + dy) * src_width + x * block) * 3;
+                for pixel in raw[offset..offset + block * 3].chunks_exact(3) {
+                    sum += pixel[0] as u32 + pixel[1] as u32 + pixel[2] as u32;
+                }
+            }
+            row[x] = (sum / (block * block) as u32) as u16;
+        }
+    });
+    LumaPlane { width, height, data }
+}
+
+// 3x3 laplacian, an output pixel is an edge if any of the stride x stride pixels it covers is one.
+// Columns after a cancel are left empty.
+pub fn edge_map(luma: &LumaPlane, stride: usize, cancel: &CancellationToken) -> Vec<Vec<bool>> {
+    let map_width = luma.width / stride;
+    let map_height = luma.height / stride;
+    (0..map_width).into_par_iter().map(|x| {
+        let mut line = vec![false; map_height];
+        if cancel.is_cancelled() {
+            return line;
+        }
+        for y in 0..map_height {
+            'block: for dx in 0..stride {
+                for dy in 0..stride {
+                    let cx = (x * stride + dx) as isize;
+                    let cy = (y * stride + dy) as isize;
+                    let mut edge = luma.get(cx, cy) as i32 * 8;
+                    for i in -1..2 {
+                        for j in -1..2 {
+                            if i == 0 && j == 0 {
+                                continue;
+                            }
+                            edge -= luma.get(cx + i, cy + j) as i32;
+                        }
+                    }
+                    if edge > 0 {
+                        line[y] = true;
+                        break 'block;
+                    }
+                }
+            }
+        }
+        line
+    }).collect::<Vec<Vec<bool>>>()
+}
+
+// Sliding window morphology along x, dilate keeps a pixel if any pixel in the window is set,
+// erode only if all of them are
+fn morph_x(map: &Vec<Vec<bool>>, radius: usize, dilate: bool) -> Vec<Vec<bool>> {
+    if radius == 0 {
+        return map.clone();
+    }
+    let width = map.len();
+    (0..width).into_par_iter().map(|x| {
+        let from = x.saturating_sub(radius);
+        let to = cmp::min(x + radius, width - 1);
+        (0..map[x].len()).map(|y| {
+            if dilate {
+                (from..=to).any(|i| map[i][y])
+            } else {
+                (from..=to).all(|i| map[i][y])
+            }
+        }).collect::<Vec<bool>>()
+    }).collect::<Vec<Vec<bool>>>()
+}
+
+fn morph_y(map: &Vec<Vec<bool>>, radius: usize, dilate: bool) -> Vec<Vec<bool>> {
+    if radius == 0 {
+        return map.clone();
+    }
+    map.par_iter().map(|column| {
+        let height = column.len();
+        (0..height).map(|y| {
+            let window = &column[y.saturating_sub(radius)..=cmp::min(y + radius, height - 1)];
+            if dilate {
+                window.contains(&true)
+            } else {
+                !window.contains(&false)
+            }
+        }).collect::<Vec<bool>>()
+    }).collect::<Vec<Vec<bool>>>()
+}
+
+// Most common short gap between edge runs, sampled on every 4th row (x) and column (y).
+// Gaps between letters of a word are by far the most frequent ones, so this is the
+// distance the closing kernel has to bridge.
+fn estimate_glyph_gaps(map: &Vec<Vec<bool>>) -> (usize, usize) {
+    let width = map.len();
+    let height = if width > 0 { map[0].len() } else { 0 };
+
+    let most_common_gap = |histogram: &[usize]| {
+        let mut best = 0;
+        for gap in 1..histogram.len() {
+            if histogram[gap] > histogram[best] {
+                best = gap;
+            }
+        }
+        best
+    };
+
+    let mut x_gaps = vec![0usize; 12];
+    for y in (0..height).step_by(4) {
+        let mut last_edge: Option<usize> = None;
+        for x in 0..width {
+            if !map[x][y] {
+                continue;
+            }
+            if let Some(last) = last_edge {
+                let gap = x - last - 1;
+                if gap > 0 && gap < x_gaps.len() {
+                    x_gaps[gap] += 1;
+                }
+            }
+            last_edge = Some(x);
+        }
+    }
+
+    let mut y_gaps = vec![0usize; 5];
+    for x in (0..width).step_by(4) {
+        let mut last_edge: Option<usize> = None;
+        for y in 0..height {
+            if !map[x][y] {
+                continue;
+            }
+            if let Some(last) = last_edge {
+                let gap = y - last - 1;
+                if gap > 0 && gap < y_gaps.len() {
+                    y_gaps[gap] += 1;
+                }
+            }
+            last_edge = Some(y);
+        }
+    }
+
+    (most_common_gap(&x_gaps), most_common_gap(&y_gaps))
+}
+
+// Closing bridges gaps without growing the glyphs, dilation grows them (boxes are shrunk again
+// after labelling). Returns the grouped map and the dilation radii.
+pub fn group_glyphs(map: &Vec<Vec<bool>>, grouping: &config::GroupingConfig) -> (Vec<Vec<bool>>, usize, usize) {
+    let (close_x, close_y) = if grouping.auto {
+        let (gap_x, gap_y) = estimate_glyph_gaps(map);
+        log::debug!("Estimated glyph gaps: {:?} {:?}", gap_x, gap_y);
+        ((gap_x + 1) / 2, (gap_y + 1) / 2)
+    } else {
+        (grouping.close_x / 2, grouping.close_y / 2)
+    };
+    let dilate_x = grouping.dilate_x / 2;
+    let dilate_y = grouping.dilate_y / 2;
+
+    let closed = morph_y(&morph_x(map, close_x, true), close_y, true);
+    let closed = morph_y(&morph_x(&closed, close_x, false), close_y, false);
+    let grouped = morph_y(&morph_x(&closed, dilate_x, true), dilate_y, true);
+    (grouped, dilate_x, dilate_y)
+}
+
+pub fn shrink_boxes(boxes: Vec<(usize, usize, usize, usize)>, radius_x: usize, radius_y: usize) -> Vec<(usize, usize, usize, usize)> {
+    boxes.into_iter().map(|(min_x, min_y, max_x, max_y)| {
+        let (min_x, max_x) = if max_x - min_x > radius_x * 2 {
+            (min_x + radius_x, max_x - radius_x)
+        } else {
+            (min_x, max_x)
+        };
+        let (min_y, max_y) = if max_y - min_y > radius_y * 2 {
+            (min_y + radius_y, max_y - radius_y)
+        } else {
+            (min_y, max_y)
+        };
+        (min_x, min_y, max_x, max_y)
+    }).collect::<Vec<(usize, usize, usize, usize)>>()
+}
+
+// Bounding boxes (padded by a pixel) of the 4-connected components of the map, a cancel stops
+// every chunk at its next column
+pub fn label_components(map: &Vec<Vec<bool>>, cancel: &CancellationToken) -> Vec<(usize, usize, usize, usize)> {
+    let num_threads = rayon::current_num_threads();
+    // println!("Num threads: {:?}", num_threads);
+    let width = map.len();
+    if width == 0 || map[0].is_empty() {
+        return Vec::new();
+    }
+    let num_chunks = num_threads;
+    // maps narrower than the number of threads get a column per chunk
+    let chunk_size = cmp::max(width / num_chunks, 1);
+    // the columns start..end of every chunk
+    let mut chunks: Vec<(usize, usize)> = Vec::new();
+    loop {
+        let start = chunks.len() * chunk_size;
+        let end = start + chunk_size;
+        if end >= width {
+            chunks.push((start, width));
+            break;
+        }
+        chunks.push((start, end));
+    }
+    log::debug!("Chunks: {:?}", chunks);
+    chunks.par_iter().enumerate().map(|(i, (start, end))| {
+        let width = map.len();
+        let height = map[0].len();
+        let mut visited = vec![vec![false; height]; width as usize];
+        let mut boxes = Vec::new();
+        for x in *start..*end {
+            if cancel.is_cancelled() {
+                break;
+            }
+            for y in 0..height {
+                if map[x][y] && !visited[x][y] {
+                    let mut start_x = x;
+                    let mut end_x = x;
+                    let mut start_y = y;
+                    let mut end_y = y;
+
+                    let mut queue = std::collections::VecDeque::new();
+                    queue.push_back((x, y));
+                    visited[x][y] = true;
+                    while let Some((x, y)) = queue.pop_front() {
+                        if x < start_x {
+                            start_x = x;
+                        }
+                        if x > end_x {
+                            end_x = x;
+                        }
+                        if y < start_y {
+                            start_y = y;
+                        }
+                        if y > end_y {
+                            end_y = y;
+                        }
+
+                        if x > 0 && map[x - 1][y] && !visited[x - 1][y] {
+                            queue.push_back((x-1, y));
+                            visited[x - 1][y] = true;
+                        }
+                        if x < width - 1 && map[x + 1][y] && !visited[x + 1][y] {
+                            queue.push_back((x+1, y));
+                            visited[x + 1][y] = true;
+                        }
+                        if y > 0 && map[x][y - 1] && !visited[x][y - 1] {
+                            queue.push_back((x, y-1));
+                            visited[x][y - 1] = true;
+                        }
+                        if y < height - 1 && map[x][y + 1] && !visited[x][y + 1] {
+                            queue.push_back((x, y+1));
+                            visited[x][y + 1] = true;
+                        }
+                    }
+
+                    // println!("Chunk {:?} Box: {:?} {:?} {:?} {:?}", i, start_x, start_y, end_x, end_y);
+                    boxes.push((cmp::max(start_x as i32 -1, 0) as usize,
+                     cmp::max(start_y as i32 -1, 0) as usize,
+                     cmp::min(end_x as i32 +1, width as i32 -1) as usize,
+                     cmp::min(end_y as i32 +1, height as i32 -1) as usize));
+                }
+            }
+        }
+        boxes
+    }).collect::<Vec<Vec<(usize, usize, usize, usize)>>>().concat()
+}
+
+// Builds the screenshot and its luma plane in one parallel pass over bands of block rows, so the
+// frame is only walked once. fill_row writes row y of the frame as RGB.
+fn convert_rows(width: usize, height: usize, block: usize, fill_row: impl Fn(usize, &mut [u8]) + Sync) -> (image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, LumaPlane) {
+    let luma_width = width / block;
+    let luma_height = height / block;
+    let mut rgb = vec![0u8; width * height * 3];
+    let luma_rows = rgb.par_chunks_mut(width * 3 * block).enumerate().map(|(band, rgb_band)| {
+        let rows = rgb_band.len() / (width * 3);
+        for row in 0..rows {
+            fill_row(band * block + row, &mut rgb_band[row * width * 3..][..width * 3]);
+        }
+        // the last rows of the frame may not fill a whole block
+        if rows < block {
+            return Vec::new();
+        }
+        // the band was just written, so averaging it for the luma row stays in cache
+        (0..luma_width).map(|x| {
+            let mut sum: u32 = 0;
+            for row in 0..block {
+                let offset = (row * width + x * block) * 3;
+                for pixel in rgb_band[offset..offset + block * 3].chunks_exact(3) {
+                    sum += pixel[0] as u32 + pixel[1] as u32 + pixel[2] as u32;
+                }
+            }
+            (sum / (block * block) as u32) as u16
+        }).collect::<Vec<u16>>()
+    }).collect::<Vec<Vec<u16>>>();
+
+    let image = image::ImageBuffer::from_raw(width as u32, height as u32, rgb).unwrap();
+    (image, LumaPlane { width: luma_width, height: luma_height, data: luma_rows.concat() })
+}
+
+// Converts packed capture bytes into the screenshot and its luma plane. channels are the byte
+// offsets of red, green and blue within a pixel of bytes_per_pixel bytes (e.g. [2, 1, 0] and 4
+// for BGRx). data has to hold height rows of stride bytes, rows may be padded after the pixels.
+pub fn convert_frame(data: &[u8], stride: usize, width: usize, height: usize, bytes_per_pixel: usize, channels: [usize; 3], block: usize) -> (image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, LumaPlane) {
+    convert_rows(width, height, block, |y, dst| {
+        let src = &data[y * stride..][..width * bytes_per_pixel];
+        for (pixel, out) in src.chunks_exact(bytes_per_pixel).zip(dst.chunks_exact_mut(3)) {
+            out[0] = pixel[channels[0]];
+            out[1] = pixel[channels[1]];
+            out[2] = pixel[channels[2]];
+        }
+    })
+}
+
+// Converts an NV12 frame: a full resolution luminance plane and a half resolution plane of
+// interleaved Cb and Cr, in BT.709 video range as macOS screen capture delivers it. The planes
+// have to hold height and (height + 1) / 2 rows of their stride.
+pub fn convert_nv12(luminance: &[u8], luminance_stride: usize, chrominance: &[u8], chrominance_stride: usize, width: usize, height: usize, block: usize) -> (image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, LumaPlane) {
+    convert_rows(width, height, block, |y, dst| {
+        let luminance_row = &luminance[y * luminance_stride..][..width];
+        let chrominance_row = &chrominance[(y / 2) * chrominance_stride..];
+        for (x, out) in dst.chunks_exact_mut(3).enumerate() {
+            // fixed point, scaled by 2^16
+            let luma = (luminance_row[x] as i32 - 16) * 76309;
+            let cb = chrominance_row[x / 2 * 2] as i32 - 128;
+            let cr = chrominance_row[x / 2 * 2 + 1] as i32 - 128;
+            out[0] = ((luma + 117489 * cr + 32768) >> 16).clamp(0, 255) as u8;
+            out[1] = ((luma - 13975 * cb - 34925 * cr + 32768) >> 16).clamp(0, 255) as u8;
+            out[2] = ((luma + 138438 * cb + 32768) >> 16).clamp(0, 255) as u8;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 5x3 pixels in 2x2 blocks: the last column and row do not fill a block
+    const WIDTH: usize = 5;
+    const HEIGHT: usize = 3;
+    const BLOCK: usize = 2;
+
+    fn colour(x: usize, y: usize) -> [u8; 3] {
+        [(x * 50) as u8, (y * 100) as u8, 200 - (x * 10 + y * 20) as u8]
+    }
+
+    // one frame per layout, written byte by byte from its name, with rows padded to stride
+    fn pack(layout: &str, stride: usize) -> Vec<u8> {
+        let mut data = vec![0u8; stride * HEIGHT];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let [r, g, b] = colour(x, y);
+                for (index, channel) in layout.chars().enumerate() {
+                    data[y * stride + x * layout.len() + index] = match channel {
+                        'R' => r,
+                        'G' => g,
+                        'B' => b,
+                        _ => 0xaa,
+                    };
+                }
+            }
+        }
+        data
+    }
+
+    fn check(image: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, luma: &LumaPlane, colour: impl Fn(usize, usize) -> [u8; 3], tolerance: i32) {
+        assert_eq!(image.dimensions(), (WIDTH as u32, HEIGHT as u32));
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let expected = colour(x, y);
+                let pixel = image.get_pixel(x as u32, y as u32).0;
+                for channel in 0..3 {
+                    assert!((pixel[channel] as i32 - expected[channel] as i32).abs() <= tolerance, "{:?} at {:?} is not {:?}", pixel, (x, y), expected);
+                }
+            }
+        }
+        // the luma plane only covers the whole blocks and averages the converted pixels
+        assert_eq!((luma.width, luma.height), (WIDTH / BLOCK, HEIGHT / BLOCK));
+        for y in 0..luma.height {
+            for x in 0..luma.width {
+                let mut sum = 0;
+                for dy in 0..BLOCK {
+                    for dx in 0..BLOCK {
+                        let pixel = image.get_pixel((x * BLOCK + dx) as u32, (y * BLOCK + dy) as u32).0;
+                        sum += pixel[0] as u32 + pixel[1] as u32 + pixel[2] as u32;
+                    }
+                }
+                assert_eq!(luma.data[y * luma.width + x] as u32, sum / (BLOCK * BLOCK) as u32);
+            }
+        }
+    }
+
+    #[test]
+    fn packed_layouts() {
+        let layouts = [("BGRA", [2, 1, 0]), ("BGR0", [2, 1, 0]), ("RGB", [0, 1, 2]), ("RGBx", [0, 1, 2]), ("XBGR", [3, 2, 1]), ("BGRx", [2, 1, 0])];
+        for (layout, channels) in layouts {
+            let bytes_per_pixel = layout.len();
+            // tight rows and rows padded to 8 bytes
+            for stride in [WIDTH * bytes_per_pixel, (WIDTH * bytes_per_pixel + 7) / 8 * 8] {
+                let data = pack(layout, stride);
+                let (image, luma) = convert_frame(&data, stride, WIDTH, HEIGHT, bytes_per_pixel, channels, BLOCK);
+                check(&image, &luma, colour, 0);
+            }
+        }
+    }
+
+    // BT.709 video range, the inverse of what convert_nv12 does
+    fn to_ycbcr([r, g, b]: [u8; 3]) -> (f32, f32, f32) {
+        let (r, g, b) = (r as f32, g as f32, b as f32);
+        let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        let cb = (b - y) / 1.8556;
+        let cr = (r - y) / 1.5748;
+        (16.0 + y * 219.0 / 255.0, 128.0 + cb * 224.0 / 255.0, 128.0 + cr * 224.0 / 255.0)
+    }
+
+    // a chroma sample covers 2x2 pixels, they get the same colour
+    fn chroma_colour(x: usize, y: usize) -> [u8; 3] {
+        colour(x / 2 * 2, y / 2 * 2)
+    }
+
+    #[test]
+    fn nv12() {
+        for (luminance_stride, chrominance_stride) in [(WIDTH, WIDTH + 1), (8, 8)] {
+            let mut luminance = vec![0u8; luminance_stride * HEIGHT];
+            let mut chrominance = vec![0u8; chrominance_stride * ((HEIGHT + 1) / 2)];
+            for y in 0..HEIGHT {
+                for x in 0..WIDTH {
+                    luminance[y * luminance_stride + x] = to_ycbcr(chroma_colour(x, y)).0.round() as u8;
+                }
+            }
+            for y in (0..HEIGHT).step_by(2) {
+                for x in (0..WIDTH).step_by(2) {
+                    let (_, cb, cr) = to_ycbcr(chroma_colour(x, y));
+                    chrominance[y / 2 * chrominance_stride + x] = cb.round() as u8;
+                    chrominance[y / 2 * chrominance_stride + x + 1] = cr.round() as u8;
+                }
+            }
+            let (image, luma) = convert_nv12(&luminance, luminance_stride, &chrominance, chrominance_stride, WIDTH, HEIGHT, BLOCK);
+            // the planes are rounded to whole values
+            check(&image, &luma, chroma_colour, 3);
+        }
+    }
+
+    #[test]
+    fn nv12_black_white_red() {
+        // 2x2 pixels with one chroma sample each: black, white and red in video range
+        for (y, cb, cr, expected) in [(16, 128, 128, [0, 0, 0]), (235, 128, 128, [255, 255, 255]), (63, 102, 240, [255, 0, 0])] {
+            let (image, luma) = convert_nv12(&[y; 4], 2, &[cb, cr], 2, 2, 2, 2);
+            for pixel in image.pixels() {
+                for channel in 0..3 {
+                    assert!((pixel.0[channel] as i32 - expected[channel] as i32).abs() <= 2, "{:?} is not {:?}", pixel.0, expected);
+                }
+            }
+            assert_eq!(luma.data.len(), 1);
+        }
+    }
+
+    #[test]
+    fn cancelled_stages_stop() {
+        let image = image::ImageBuffer::from_fn(64, 64, |x, y| if (x / 8 + y / 8) % 2 == 0 { image::Rgb([255u8, 255, 255]) } else { image::Rgb([0u8, 0, 0]) });
+        let map = edge_map(&luma_plane(&image, 1, &CancellationToken::new()), 1, &CancellationToken::new());
+        assert!(!label_components(&map, &CancellationToken::new()).is_empty());
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let luma = luma_plane(&image, 1, &cancel);
+        assert!(luma.data.iter().all(|value| *value == 0));
+        assert!(edge_map(&luma, 1, &cancel).iter().flatten().all(|edge| !edge));
+        assert!(label_components(&map, &cancel).is_empty());
+    }
+
+    // a vertical bar in the last column of a frame width pixels wide
+    fn bar(width: u32) -> image::ImageBuffer<image::Rgb<u8>, Vec<u8>> {
+        image::ImageBuffer::from_fn(width, 16, |x, y| if x + 1 == width && (4..12).contains(&y) { image::Rgb([0u8, 0, 0]) } else { image::Rgb([255u8, 255, 255]) })
+    }
+
+    #[test]
+    fn narrow_inputs() {
+        let cancel = CancellationToken::new();
+        for width in [0, 1, 3] {
+            let luma = luma_plane(&bar(width), 1, &cancel);
+            assert_eq!((luma.width, luma.height, luma.data.len()), (width as usize, 16, width as usize * 16));
+            let map = edge_map(&luma, 1, &cancel);
+            let boxes = label_components(&map, &cancel);
+            assert_eq!(boxes.is_empty(), width == 0);
+            assert!(boxes.iter().all(|box_| box_.2 < width as usize && box_.3 < 16));
+            // narrower than a block, or only the white columns are averaged
+            let luma = luma_plane(&bar(width), 2, &cancel);
+            assert_eq!(luma.width, width as usize / 2);
+            assert!(label_components(&edge_map(&luma, 1, &cancel), &cancel).is_empty());
+        }
+        assert!(label_components(&Vec::new(), &cancel).is_empty());
+        assert!(label_components(&vec![Vec::new(); 3], &cancel).is_empty());
+        // only the last column is set, it is scanned whatever the number of threads
+        let mut map = vec![vec![false; 5]; 3];
+        map[2][2] = true;
+        assert_eq!(label_components(&map, &cancel), vec![(1, 1, 2, 3)]);
+    }
+}
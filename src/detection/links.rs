@@ -0,0 +1,85 @@
+use color_space::Hsv;
+use color_space::Rgb;
+use rayon::iter::IntoParallelRefIterator;
+use rayon::iter::ParallelIterator;
+
+use super::{of_kind, DetectionContext, Detector, Element, ElementKind};
+
+// Runs of saturated blue inside text lines
+pub struct LinkDetector;
+
+impl Detector for LinkDetector {
+    fn name(&self) -> &'static str {
+        "links"
+    }
+
+    fn detect(&self, context: &DetectionContext, elements: &[Element]) -> Vec<Element> {
+        let screenshot = context.screenshot;
+        let lines = of_kind(elements, ElementKind::Line);
+        lines.par_iter().map(|(min_x, min_y, max_x, max_y)| {
+            if context.cancelled() {
+                return Vec::new();
+            }
+            // go through each column, convert each pixel to hsv, and check if it intense.
+            let mut max_values = vec![false; max_x - min_x];
+            for x in *min_x..*max_x {
+                for y in *min_y..*max_y {
+                    let pixel = screenshot.get_pixel(x as u32, y as u32);
+                    let rgb = Rgb::new(pixel.0[0] as f64, pixel.0[1] as f64, pixel.0[2] as f64);
+                    let hsv = Hsv::from(rgb);
+                    let intensity = hsv.s;
+                    if intensity > 0.5 && hsv.h > 200.0 && hsv.h < 270.0 {
+                        max_values[x - min_x] = true;
+                    }
+                }
+            }
+            // create boxes from max values, gap of 5 or more is new box, min length is 15
+            let mut boxes = Vec::new();
+            let mut start = -1;
+            let mut end = -1;
+            let mut gap = 0;
+            for i in 0..max_values.len() {
+                if start == -1 {
+                    if max_values[i] {
+                        start = i as i32;
+                        end = i as i32;
+                        gap = 0;
+                    }
+                } else {
+                    if max_values[i] {
+                        end = i as i32;
+                        gap = 0;
+                    } else {
+                        gap += 1;
+                        if gap >= 15 {
+                            if end - start >= 50 {
+                                boxes.push((start as usize + min_x, *min_y, end as usize + min_x, *max_y));
+                            }
+                            start = -1;
+                            end = -1;
+                        }
+                    }
+                }
+            }
+            // last box
+            if start != -1 && end != -1 && end - start >= 50 {
+                boxes.push((start as usize + min_x, *min_y, end as usize + min_x, *max_y));
+            }
+
+            // filter boxes where the # of max values is less than 70% of the width
+            boxes = boxes.into_iter().filter(|(lmin_x, min_y, lmax_x, max_y)| {
+                let mut white = 0;
+                for x in *lmin_x..*lmax_x {
+                    if max_values[x - min_x] {
+                        white += 1;
+                    }
+                }
+                white as f32 / (lmax_x - lmin_x) as f32 > 0.5
+            }).collect::<Vec<(usize, usize, usize, usize)>>();
+
+            boxes
+        }).collect::<Vec<Vec<(usize, usize, usize, usize)>>>().concat().into_iter().map(|link| {
+            Element::new(ElementKind::Link, link)
+        }).collect::<Vec<Element>>()
+    }
+}
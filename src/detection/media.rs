@@ -0,0 +1,138 @@
+use std::cmp;
+
+use rayon::iter::IntoParallelRefIterator;
+use rayon::iter::ParallelIterator;
+
+use crate::config;
+
+use super::{DetectionContext, Detector, Element, ElementKind};
+
+pub struct RegionStats {
+    pub dominant_colour: f32,
+    pub colour_stddev: f32,
+    pub edge_density: f32,
+    pub motion: f32,
+}
+
+// Samples every 4th pixel of a region (in screenshot coordinates)
+pub fn region_stats(screenshot: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, previous: Option<&image::ImageBuffer<image::Rgb<u8>, Vec<u8>>>, map: &Vec<Vec<bool>>, (min_x, min_y, max_x, max_y): (usize, usize, usize, usize), scale: usize) -> RegionStats {
+    let mut histogram = vec![0u32; 4096];
+    let mut sum = [0f64; 3];
+    let mut sum_sq = [0f64; 3];
+    let mut samples = 0u32;
+    let mut moved = 0u32;
+    let pixel_max_x = cmp::min(max_x * scale, screenshot.width() as usize - 1);
+    let pixel_max_y = cmp::min(max_y * scale, screenshot.height() as usize - 1);
+    for y in (min_y * scale..pixel_max_y).step_by(4) {
+        for x in (min_x * scale..pixel_max_x).step_by(4) {
+            let pixel = screenshot.get_pixel(x as u32, y as u32).0;
+            // 4 bits per channel
+            let bin = ((pixel[0] >> 4) as usize) << 8 | ((pixel[1] >> 4) as usize) << 4 | (pixel[2] >> 4) as usize;
+            histogram[bin] += 1;
+            for c in 0..3 {
+                sum[c] += pixel[c] as f64;
+                sum_sq[c] += pixel[c] as f64 * pixel[c] as f64;
+            }
+            samples += 1;
+
+            if let Some(previous) = previous {
+                let before = previous.get_pixel(x as u32, y as u32).0;
+                let diff = (0..3).map(|c| (pixel[c] as i32 - before[c] as i32).abs()).sum::<i32>();
+                if diff > 24 {
+                    moved += 1;
+                }
+            }
+        }
+    }
+    if samples == 0 {
+        return RegionStats { dominant_colour: 1.0, colour_stddev: 0.0, edge_density: 0.0, motion: 0.0 };
+    }
+
+    let mut variance = 0.0;
+    for c in 0..3 {
+        let mean = sum[c] / samples as f64;
+        variance += sum_sq[c] / samples as f64 - mean * mean;
+    }
+
+    let mut edges = 0u32;
+    let mut total = 0u32;
+    for x in min_x..cmp::min(max_x + 1, map.len()) {
+        for y in min_y..cmp::min(max_y + 1, map[x].len()) {
+            total += 1;
+            if map[x][y] {
+                edges += 1;
+            }
+        }
+    }
+
+    RegionStats {
+        dominant_colour: *histogram.iter().max().unwrap() as f32 / samples as f32,
+        colour_stddev: (variance / 3.0).max(0.0).sqrt() as f32,
+        edge_density: edges as f32 / cmp::max(total, 1) as f32,
+        motion: moved as f32 / samples as f32,
+    }
+}
+
+// Large components that look like videos, canvases, maps or photos rather than UI panels. UI
+// panels are mostly one background colour, media is not. Regions nested in another media region
+// are dropped.
+pub fn detect_media(screenshot: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, previous: Option<&image::ImageBuffer<image::Rgb<u8>, Vec<u8>>>, map: &Vec<Vec<bool>>, boxes: &Vec<(usize, usize, usize, usize)>, scale: usize, media: &config::MediaConfig) -> Vec<(usize, usize, usize, usize)> {
+    let candidates = boxes.par_iter().filter(|(min_x, min_y, max_x, max_y)| {
+        (max_x - min_x) >= media.min_width && (max_y - min_y) >= media.min_height
+    }).filter(|box_| {
+        let stats = region_stats(screenshot, previous, map, **box_, scale);
+        if previous.is_some() && stats.motion >= media.min_motion {
+            return true;
+        }
+        stats.dominant_colour < media.max_dominant_colour
+            && (stats.colour_stddev >= media.min_colour_stddev || stats.edge_density >= media.min_edge_density)
+    }).map(|box_| *box_).collect::<Vec<(usize, usize, usize, usize)>>();
+
+    candidates.iter().filter(|(min_x, min_y, max_x, max_y)| {
+        !candidates.iter().any(|outer| {
+            outer != &(*min_x, *min_y, *max_x, *max_y) && outer.0 <= *min_x && outer.1 <= *min_y && outer.2 >= *max_x && outer.3 >= *max_y
+        })
+    }).map(|box_| *box_).collect::<Vec<(usize, usize, usize, usize)>>()
+}
+
+// Click targets inside a media region (screenshot coordinates): the centre, the four corners and
+// the left, middle and right of the player control strip along the bottom
+pub fn media_points(regions: &Vec<(usize, usize, usize, usize)>) -> Vec<(usize, usize, usize, usize)> {
+    let point = |x: usize, y: usize| (x.saturating_sub(4), y.saturating_sub(4), x + 4, y + 4);
+    let mut points = Vec::new();
+    for (min_x, min_y, max_x, max_y) in regions {
+        let width = max_x - min_x;
+        let height = max_y - min_y;
+        let inset = cmp::min(width, height) / 20;
+        let strip_y = max_y - height / 20;
+        points.push(point(min_x + width / 2, min_y + height / 2));
+        points.push(point(min_x + inset, min_y + inset));
+        points.push(point(max_x - inset, min_y + inset));
+        points.push(point(min_x + inset, max_y - inset));
+        points.push(point(max_x - inset, max_y - inset));
+        points.push(point(min_x + width / 20, strip_y));
+        points.push(point(min_x + width / 2, strip_y));
+        points.push(point(max_x - width / 20, strip_y));
+    }
+    points
+}
+
+// Media regions and the click points inside them
+pub struct MediaDetector;
+
+impl Detector for MediaDetector {
+    fn name(&self) -> &'static str {
+        "media"
+    }
+
+    fn detect(&self, context: &DetectionContext, _elements: &[Element]) -> Vec<Element> {
+        let regions = detect_media(context.screenshot, context.motion_frame, &context.edge_map, &context.components, context.scale, &context.config.media);
+        let regions = regions.iter().map(|region| context.unmap(region)).collect::<Vec<(usize, usize, usize, usize)>>();
+
+        let mut elements = regions.iter().map(|region| Element::new(ElementKind::Media, *region)).collect::<Vec<Element>>();
+        for point in media_points(&regions) {
+            elements.push(Element::new(ElementKind::MediaPoint, point));
+        }
+        elements
+    }
+}
@@ -0,0 +1,570 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::config;
+use crate::trace;
+
+pub mod accessibility;
+pub mod boxes;
+pub mod browser;
+pub mod cache;
+#[cfg(feature = "classifier")]
+pub mod classifier;
+pub mod cursor;
+pub mod edges;
+pub mod links;
+pub mod media;
+pub mod templates;
+pub mod text;
+
+// (min_x, min_y, max_x, max_y)
+pub type Rect = (usize, usize, usize, usize);
+pub type Screenshot = image::ImageBuffer<image::Rgb<u8>, Vec<u8>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ElementKind {
+    BigBox,
+    Line,
+    SmallImage,
+    Media,
+    MediaPoint,
+    Link,
+    // elements with a fixed label, e.g. matched icon templates
+    Pinned,
+    // text inputs, hinted together with the boxes
+    Field,
+}
+
+// A detected element, in screenshot coordinates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Element {
+    pub kind: ElementKind,
+    pub rect: Rect,
+    pub label: Option<String>,
+}
+
+impl Element {
+    pub fn new(kind: ElementKind, rect: Rect) -> Self {
+        Element { kind, rect, label: None }
+    }
+
+    pub fn labelled(kind: ElementKind, rect: Rect, label: String) -> Self {
+        Element { kind, rect, label: Some(label) }
+    }
+}
+
+// whether the intersection covers more than half of the smaller rect
+pub fn overlaps(a: &Rect, b: &Rect) -> bool {
+    let width = a.2.min(b.2) as i64 - a.0.max(b.0) as i64;
+    let height = a.3.min(b.3) as i64 - a.1.max(b.1) as i64;
+    if width <= 0 || height <= 0 {
+        return false;
+    }
+    let area = |rect: &Rect| ((rect.2 - rect.0) * (rect.3 - rect.1)) as i64;
+    width * height * 2 > area(a).min(area(b))
+}
+
+pub fn of_kind(elements: &[Element], kind: ElementKind) -> Vec<Rect> {
+    elements.iter().filter(|element| element.kind == kind).map(|element| element.rect).collect::<Vec<Rect>>()
+}
+
+// Aborts a detection job, e.g. when the shortcut is pressed again. Checked between the stages
+// and inside the parallel loops of the slower detectors.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken(Arc::new(AtomicBool::new(false)))
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Everything computed once per frame and shared by all detectors. The edge map and components
+// are in downsampled coordinates, multiply by scale (or use unmap) to get back to the screenshot.
+// The screenshot may be a region cut out of the screen at origin, see region.
+pub struct DetectionContext<'a> {
+    pub screenshot: &'a Screenshot,
+    // a second frame taken shortly after the screenshot, if motion detection is enabled
+    pub motion_frame: Option<&'a Screenshot>,
+    pub config: &'a config::Config,
+    pub cancel: CancellationToken,
+    // detecting ahead of time (prefetch), the foreground only detectors are skipped
+    pub background: bool,
+    pub origin: (usize, usize),
+    pub block: usize,
+    pub stride: usize,
+    pub scale: usize,
+    pub luma: edges::LumaPlane,
+    pub edge_map: Vec<Vec<bool>>,
+    pub components: Vec<Rect>,
+    pub body_text_height: usize,
+    pub max_text_height: usize,
+}
+
+impl<'a> DetectionContext<'a> {
+    // luma is the plane the capture already converted, if any, it is computed otherwise
+    pub fn new(screenshot: &'a Screenshot, luma: Option<edges::LumaPlane>, motion_frame: Option<&'a Screenshot>, config: &'a config::Config, cancel: &CancellationToken) -> Self {
+        let (block, stride) = edges::detection_scale(screenshot.height());
+        Self::build(screenshot, luma, motion_frame, config, cancel, (0, 0), block, stride, None)
+    }
+
+    // A context for a region of a frame that was detected before, cut out at origin. Uses the
+    // scale and text heights of the full frame, a small region has too few glyphs to measure
+    // them and has to be downsampled the same way to find the same boxes.
+    pub fn region(screenshot: &'a Screenshot, motion_frame: Option<&'a Screenshot>, config: &'a config::Config, cancel: &CancellationToken, origin: (usize, usize), frame: &cache::FrameParams) -> Self {
+        Self::build(screenshot, None, motion_frame, config, cancel, origin, frame.block, frame.stride, Some((frame.body_text_height, frame.max_text_height)))
+    }
+
+    // A context for the whole-screen detectors when only regions of the frame were detected
+    // again. The components are the cached ones merged with those of the regions, there is no
+    // luma plane or edge map, so it can not tell text (is_text) from boxes.
+    pub fn merged(screenshot: &'a Screenshot, config: &'a config::Config, cancel: &CancellationToken, frame: &cache::FrameParams, components: Vec<Rect>) -> Self {
+        DetectionContext {
+            screenshot,
+            motion_frame: None,
+            config,
+            cancel: cancel.clone(),
+            background: false,
+            origin: (0, 0),
+            block: frame.block,
+            stride: frame.stride,
+            scale: frame.block * frame.stride,
+            luma: edges::LumaPlane { width: 0, height: 0, data: Vec::new() },
+            edge_map: Vec::new(),
+            components,
+            body_text_height: frame.body_text_height,
+            max_text_height: frame.max_text_height,
+        }
+    }
+
+    // A cancelled job skips the remaining stages and ends up without components
+    fn build(screenshot: &'a Screenshot, luma: Option<edges::LumaPlane>, motion_frame: Option<&'a Screenshot>, config: &'a config::Config, cancel: &CancellationToken, origin: (usize, usize), block: usize, stride: usize, text_heights: Option<(usize, usize)>) -> Self {
+        let start = std::time::Instant::now();
+        let trace_start = trace::now_us();
+        let scale = block * stride;
+        let luma = match luma {
+            Some(luma) if luma.width == screenshot.width() as usize / block && luma.height == screenshot.height() as usize / block => luma,
+            _ => edges::luma_plane(screenshot, block, cancel),
+        };
+        let edge_map = edges::edge_map(&luma, stride, cancel);
+        log::debug!("Scale: {:?}", scale);
+        log::debug!("Downsampled Elapsed: {:?}", start.elapsed());
+        trace::record("edges", trace_start);
+
+        let components = if cancel.is_cancelled() {
+            Vec::new()
+        } else {
+            let start = std::time::Instant::now();
+            let trace_start = trace::now_us();
+            let (grouped_map, dilate_x, dilate_y) = edges::group_glyphs(&edge_map, &config.grouping);
+            log::debug!("Grouping Elapsed: {:?}", start.elapsed());
+            trace::record("grouping", trace_start);
+
+            if cancel.is_cancelled() {
+                Vec::new()
+            } else {
+                let start = std::time::Instant::now();
+                let trace_start = trace::now_us();
+                let components = edges::shrink_boxes(edges::label_components(&grouped_map, cancel), dilate_x, dilate_y);
+                log::debug!("Boxes Elapsed: {:?}", start.elapsed());
+                trace::record("components", trace_start);
+                // the chunks stop at the cancel, their components are cut off
+                if cancel.is_cancelled() {
+                    Vec::new()
+                } else {
+                    components
+                }
+            }
+        };
+
+        let (body_text_height, max_text_height) = text_heights.unwrap_or_else(|| text::text_heights(&components, &config.text));
+        log::debug!("Max text height: {:?}", max_text_height);
+
+        DetectionContext {
+            screenshot,
+            motion_frame,
+            config,
+            cancel: cancel.clone(),
+            background: false,
+            origin,
+            block,
+            stride,
+            scale,
+            luma,
+            edge_map,
+            components,
+            body_text_height,
+            max_text_height,
+        }
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    pub fn params(&self) -> cache::FrameParams {
+        cache::FrameParams {
+            block: self.block,
+            stride: self.stride,
+            body_text_height: self.body_text_height,
+            max_text_height: self.max_text_height,
+        }
+    }
+
+    // screen coordinates to screenshot coordinates, None if the rect is not in the screenshot
+    pub fn from_screen(&self, rect: Rect) -> Option<Rect> {
+        let max_x = (rect.2.checked_sub(self.origin.0)?).min(self.screenshot.width() as usize - 1);
+        let max_y = (rect.3.checked_sub(self.origin.1)?).min(self.screenshot.height() as usize - 1);
+        let min_x = rect.0.saturating_sub(self.origin.0);
+        let min_y = rect.1.saturating_sub(self.origin.1);
+        if min_x >= max_x || min_y >= max_y {
+            return None;
+        }
+        Some((min_x, min_y, max_x, max_y))
+    }
+
+    pub fn to_screen(&self, rect: Rect) -> Rect {
+        (rect.0 + self.origin.0, rect.1 + self.origin.1, rect.2 + self.origin.0, rect.3 + self.origin.1)
+    }
+
+    // whether a component (downsampled coordinates) is a glyph or word rather than a box
+    pub fn is_text(&self, component: &Rect) -> bool {
+        let height = component.3 - component.1;
+        height <= self.body_text_height || (height <= self.max_text_height && !text::is_framed(&self.edge_map, *component))
+    }
+
+    pub fn unmap(&self, rect: &Rect) -> Rect {
+        (
+            rect.0 * self.scale,
+            rect.1 * self.scale,
+            rect.2 * self.scale + self.scale - 1,
+            rect.3 * self.scale + self.scale - 1,
+        )
+    }
+
+    pub fn downsample(&self, rect: &Rect) -> Rect {
+        (rect.0 / self.scale, rect.1 / self.scale, rect.2 / self.scale, rect.3 / self.scale)
+    }
+}
+
+// The detectors run phase by phase, each phase in registry order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    // the detectors that look at the pixels around each element, they can run on regions
+    Regional,
+    // the whole-screen detectors that look at the pixels, e.g. templates
+    Screen,
+    // the detectors with sources outside of the screenshot, their elements are never cached
+    Sources,
+}
+
+// A source of elements. Detectors run in registry order and see the elements produced by the
+// detectors before them, so e.g. the link detector scans the lines found by the line detector.
+// Register your own with Registry::register to add elements without touching the main loop.
+pub trait Detector: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn detect(&self, context: &DetectionContext, elements: &[Element]) -> Vec<Element>;
+
+    // whether the elements of this detector are more accurate than the earlier ones, which are
+    // then dropped where they overlap
+    fn supersedes(&self) -> bool {
+        false
+    }
+
+    // Between superseding detectors, the elements of the higher precedence are kept where they
+    // overlap, whichever runs first
+    fn precedence(&self) -> u32 {
+        0
+    }
+
+    // whether detect returns the complete element list, e.g. the earlier elements with new kinds
+    fn replaces(&self) -> bool {
+        false
+    }
+
+    // whether the detector only runs when hints were asked for, not in the background, e.g.
+    // because it moves the pointer or is too slow to run all the time
+    fn foreground_only(&self) -> bool {
+        false
+    }
+
+    // Whether the detector looks at the whole screen rather than the pixels around each element,
+    // e.g. sources outside the screenshot or the best match across the screen. Whole-screen
+    // detectors run after the others, and once on the full frame when only regions of it are
+    // detected again.
+    fn whole_screen(&self) -> bool {
+        false
+    }
+
+    // Whether the elements only depend on the screenshot, so they can be cached with it. The
+    // others (browser, accessibility, cursor) are whole-screen, run last and run again when the
+    // frame is taken from the cache.
+    fn from_pixels(&self) -> bool {
+        true
+    }
+}
+
+fn phase(detector: &dyn Detector) -> Phase {
+    if !detector.from_pixels() {
+        Phase::Sources
+    } else if detector.whole_screen() {
+        Phase::Screen
+    } else {
+        Phase::Regional
+    }
+}
+
+pub struct Registry {
+    detectors: Vec<Box<dyn Detector>>,
+    disabled: Vec<String>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry {
+            detectors: Vec::new(),
+            disabled: Vec::new(),
+        }
+    }
+
+    // the built in detectors, in the order they depend on each other
+    pub fn with_defaults(config: &config::Config) -> Self {
+        let mut registry = Registry::new();
+        registry.register(Box::new(boxes::BigBoxDetector));
+        registry.register(Box::new(media::MediaDetector));
+        registry.register(Box::new(text::LineDetector));
+        registry.register(Box::new(links::LinkDetector));
+        registry.register(Box::new(boxes::SmallImageDetector));
+        registry.register(Box::new(templates::TemplateDetector::load(&config.templates)));
+        registry.register(Box::new(browser::BrowserDetector::new(&config.browser)));
+        #[cfg(feature = "classifier")]
+        if let Some(classifier) = classifier::ClassifierDetector::load(&config.classifier) {
+            registry.register(Box::new(classifier));
+        }
+        if config.cursor.enabled {
+            if let Some(cursor) = cursor::CursorDetector::connect(&config.cursor) {
+                registry.register(Box::new(cursor));
+            }
+        }
+        if config.accessibility.enabled {
+            registry.register(Box::new(accessibility::AccessibilityDetector::connect(&config.accessibility)));
+        }
+        registry
+    }
+
+    pub fn register(&mut self, detector: Box<dyn Detector>) {
+        self.detectors.push(detector);
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.detectors.iter().map(|detector| detector.name()).collect::<Vec<&'static str>>()
+    }
+
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) {
+        self.disabled.retain(|disabled| disabled != name);
+        if !enabled {
+            self.disabled.push(name.to_string());
+        }
+    }
+
+    // Moves the named detectors to the front, in the given order. Detectors that are not listed
+    // keep their relative order after them.
+    pub fn set_order(&mut self, names: &[String]) {
+        let mut ordered: Vec<Box<dyn Detector>> = Vec::new();
+        for name in names {
+            if let Some(index) = self.detectors.iter().position(|detector| detector.name() == name.as_str()) {
+                ordered.push(self.detectors.remove(index));
+            } else {
+                log::warn!("Unknown detector {:?}", name);
+            }
+        }
+        ordered.append(&mut self.detectors);
+        self.detectors = ordered;
+    }
+
+    pub fn configure(&mut self, detectors: &config::DetectorConfig) {
+        self.set_order(&detectors.order);
+        for name in &detectors.disabled {
+            self.set_enabled(name, false);
+        }
+    }
+
+    // Runs the detectors that are not whole-screen on the given regions of a frame (e.g. from
+    // cache::dirty_regions), each as if it was part of the full frame. Returns the elements in
+    // screen coordinates and the components in the downsampled coordinates of the full frame.
+    pub fn run_regions(&self, screenshot: &Screenshot, motion_frame: Option<&Screenshot>, config: &config::Config, cancel: &CancellationToken, background: bool, regions: &[Rect], frame: &cache::FrameParams) -> (Vec<Element>, Vec<Rect>) {
+        let mut elements = Vec::new();
+        let mut components = Vec::new();
+        for region in regions {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let (min_x, min_y, max_x, max_y) = *region;
+            let width = (max_x - min_x + 1) as u32;
+            let height = (max_y - min_y + 1) as u32;
+            let crop = image::imageops::crop_imm(screenshot, min_x as u32, min_y as u32, width, height).to_image();
+            let motion_crop = motion_frame.map(|motion_frame| image::imageops::crop_imm(motion_frame, min_x as u32, min_y as u32, width, height).to_image());
+            let mut context = DetectionContext::region(&crop, motion_crop.as_ref(), config, cancel, (min_x, min_y), frame);
+            context.background = background;
+            elements.extend(self.run_detectors(&context, Vec::new(), Phase::Regional, &mut |_| {}).into_iter().map(|mut element| {
+                element.rect = context.to_screen(element.rect);
+                element
+            }));
+            // the origin is aligned to the scale, see cache::dirty_regions
+            let (offset_x, offset_y) = (min_x / context.scale, min_y / context.scale);
+            components.extend(context.components.iter().map(|component| (component.0 + offset_x, component.1 + offset_y, component.2 + offset_x, component.3 + offset_y)));
+        }
+        (elements, components)
+    }
+
+    pub fn run(&self, context: &DetectionContext) -> Vec<Element> {
+        self.run_streaming(context, &mut |_| {})
+    }
+
+    // Like run, but hands the elements found so far to on_update after every detector, so they
+    // can be shown before the slower detectors are done
+    pub fn run_streaming(&self, context: &DetectionContext, on_update: &mut dyn FnMut(&[Element])) -> Vec<Element> {
+        let elements = self.run_detectors(context, Vec::new(), Phase::Regional, on_update);
+        let elements = self.run_detectors(context, elements, Phase::Screen, on_update);
+        self.run_detectors(context, elements, Phase::Sources, on_update)
+    }
+
+    // Runs the detectors of a phase, in registry order, on top of the given elements
+    pub fn run_detectors(&self, context: &DetectionContext, elements: Vec<Element>, phase: Phase, on_update: &mut dyn FnMut(&[Element])) -> Vec<Element> {
+        let mut elements = elements;
+        // the elements of the superseding detectors so far, with their precedence
+        let mut superseding: Vec<(Rect, u32)> = Vec::new();
+        for detector in self.detectors.iter().filter(|detector| self::phase(detector.as_ref()) == phase) {
+            if context.cancelled() {
+                log::debug!("Cancelled before {}", detector.name());
+                break;
+            }
+            if self.disabled.iter().any(|disabled| disabled == detector.name()) || (context.background && detector.foreground_only()) {
+                continue;
+            }
+            let start = std::time::Instant::now();
+            let trace_start = trace::now_us();
+            let mut found = detector.detect(context, &elements);
+            trace::record(detector.name(), trace_start);
+            if detector.replaces() {
+                log::debug!("{} returned {:?} Elapsed: {:?}", detector.name(), found.len(), start.elapsed());
+                elements = found;
+                on_update(&elements);
+                continue;
+            }
+            if detector.supersedes() {
+                let precedence = detector.precedence();
+                found.retain(|new| !superseding.iter().any(|(rect, other)| *other > precedence && overlaps(rect, &new.rect)));
+                elements.retain(|element| {
+                    element.kind == ElementKind::Pinned || !found.iter().any(|new| overlaps(&element.rect, &new.rect))
+                });
+                superseding.extend(found.iter().map(|new| (new.rect, precedence)));
+            }
+            log::debug!("{} found {:?} Elapsed: {:?}", detector.name(), found.len(), start.elapsed());
+            elements.append(&mut found);
+            on_update(&elements);
+        }
+        elements
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // finds one element at rect
+    struct Stub {
+        name: &'static str,
+        rect: Rect,
+        whole_screen: bool,
+        from_pixels: bool,
+        foreground_only: bool,
+        // superseding, with this precedence
+        precedence: Option<u32>,
+    }
+
+    impl Stub {
+        fn new(name: &'static str, x: usize, whole_screen: bool, from_pixels: bool) -> Self {
+            Stub { name, rect: (x, 0, x, 0), whole_screen, from_pixels, foreground_only: false, precedence: None }
+        }
+    }
+
+    impl Detector for Stub {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn supersedes(&self) -> bool {
+            self.precedence.is_some()
+        }
+
+        fn precedence(&self) -> u32 {
+            self.precedence.unwrap_or(0)
+        }
+
+        fn whole_screen(&self) -> bool {
+            self.whole_screen
+        }
+
+        fn from_pixels(&self) -> bool {
+            self.from_pixels
+        }
+
+        fn foreground_only(&self) -> bool {
+            self.foreground_only
+        }
+
+        fn detect(&self, _context: &DetectionContext, _elements: &[Element]) -> Vec<Element> {
+            vec![Element::new(ElementKind::BigBox, self.rect)]
+        }
+    }
+
+    fn run(registry: &Registry, background: bool) -> Vec<&'static str> {
+        let screenshot = Screenshot::new(16, 16);
+        let config = config::Config::default();
+        let frame = cache::FrameParams { block: 1, stride: 1, body_text_height: 0, max_text_height: 0 };
+        let mut context = DetectionContext::merged(&screenshot, &config, &CancellationToken::new(), &frame, Vec::new());
+        context.background = background;
+        let names = ["regional", "screen", "source", "low", "high"];
+        registry.run(&context).iter().map(|element| names[element.rect.0]).collect::<Vec<&'static str>>()
+    }
+
+    #[test]
+    fn phases_run_in_order() {
+        let mut registry = Registry::new();
+        // registered out of phase order
+        registry.register(Box::new(Stub { foreground_only: true, ..Stub::new("source", 2, true, false) }));
+        registry.register(Box::new(Stub::new("screen", 1, true, true)));
+        registry.register(Box::new(Stub::new("regional", 0, false, true)));
+        assert_eq!(run(&registry, false), vec!["regional", "screen", "source"]);
+        assert_eq!(run(&registry, true), vec!["regional", "screen"]);
+    }
+
+    #[test]
+    fn precedence_wins_in_any_order() {
+        // all three overlap, the higher precedence is kept whether it runs first or last
+        let regional = || Box::new(Stub { rect: (0, 0, 8, 8), ..Stub::new("regional", 0, false, true) });
+        let low = || Box::new(Stub { rect: (3, 0, 8, 8), precedence: Some(1), ..Stub::new("low", 3, true, false) });
+        let high = || Box::new(Stub { rect: (4, 0, 8, 8), precedence: Some(2), ..Stub::new("high", 4, true, false) });
+
+        let mut registry = Registry::new();
+        registry.register(regional());
+        registry.register(low());
+        registry.register(high());
+        assert_eq!(run(&registry, false), vec!["high"]);
+
+        let mut registry = Registry::new();
+        registry.register(regional());
+        registry.register(high());
+        registry.register(low());
+        assert_eq!(run(&registry, false), vec!["high"]);
+    }
+}
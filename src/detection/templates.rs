@@ -0,0 +1,444 @@
+use image::imageops::FilterType;
+use rayon::iter::IntoParallelRefIterator;
+use rayon::iter::ParallelIterator;
+
+use crate::config;
+
+use super::{overlaps, DetectionContext, Detector, Element, ElementKind, Rect, Screenshot};
+
+struct Template {
+    label: String,
+    width: u32,
+    height: u32,
+    // mean subtracted gray values, 0 where the png is transparent
+    pixels: Vec<f32>,
+    // the opaque pixels, the others are not compared
+    mask: Vec<bool>,
+    opaque: bool,
+    count: f32,
+    norm: f32,
+    // the template at half its size, to find the spots in a component worth a full search
+    coarse: Option<Box<Template>>,
+}
+
+fn gray(pixel: &image::Rgb<u8>) -> f32 {
+    (pixel.0[0] as f32 + pixel.0[1] as f32 + pixel.0[2] as f32) / 3.0
+}
+
+// Gray values of the surroundings of a component, with integral images of the values and their
+// squares, so the mean and energy under an opaque template are looked up at every offset
+struct Window {
+    width: u32,
+    height: u32,
+    values: Vec<f32>,
+    sums: Vec<f64>,
+    squares: Vec<f64>,
+}
+
+impl Window {
+    fn new(image: &Screenshot) -> Window {
+        let width = image.width();
+        let height = image.height();
+        let values = image.pixels().map(gray).collect::<Vec<f32>>();
+        let stride = width as usize + 1;
+        let mut sums = vec![0.0; stride * (height as usize + 1)];
+        let mut squares = vec![0.0; stride * (height as usize + 1)];
+        for y in 0..height as usize {
+            let mut row_sum = 0.0;
+            let mut row_squares = 0.0;
+            for x in 0..width as usize {
+                let value = values[y * width as usize + x] as f64;
+                row_sum += value;
+                row_squares += value * value;
+                sums[(y + 1) * stride + x + 1] = sums[y * stride + x + 1] + row_sum;
+                squares[(y + 1) * stride + x + 1] = squares[y * stride + x + 1] + row_squares;
+            }
+        }
+        Window { width, height, values, sums, squares }
+    }
+
+    // sum of the values and of their squares in the rect at (x0, y0)
+    fn rect_sums(&self, x0: u32, y0: u32, width: u32, height: u32) -> (f64, f64) {
+        let stride = self.width as usize + 1;
+        let (x0, y0, x1, y1) = (x0 as usize, y0 as usize, (x0 + width) as usize, (y0 + height) as usize);
+        let sum = |table: &Vec<f64>| table[y1 * stride + x1] - table[y0 * stride + x1] - table[y1 * stride + x0] + table[y0 * stride + x0];
+        (sum(&self.sums), sum(&self.squares))
+    }
+
+    // every offset at which the template fits inside
+    fn offsets(&self, template: &Template) -> impl Iterator<Item = (u32, u32)> {
+        let (columns, rows) = (self.width - template.width, self.height - template.height);
+        (0..=rows).flat_map(move |y0| (0..=columns).map(move |x0| (x0, y0)))
+    }
+}
+
+impl Template {
+    fn load(path: &std::path::Path, label: String) -> Result<Template, Box<dyn std::error::Error>> {
+        Template::from_image(&image::open(path)?.to_rgba8(), label)
+    }
+
+    fn from_image(image: &image::RgbaImage, label: String) -> Result<Template, Box<dyn std::error::Error>> {
+        let mut template = Template::build(image, label.clone())?;
+        if image.width() >= 8 && image.height() >= 8 {
+            let half = image::imageops::resize(image, image.width() / 2, image.height() / 2, FilterType::Triangle);
+            template.coarse = Template::build(&half, label).ok().map(Box::new);
+        }
+        Ok(template)
+    }
+
+    // pixels that are less than half opaque are left out of the match
+    fn build(image: &image::RgbaImage, label: String) -> Result<Template, Box<dyn std::error::Error>> {
+        let mask = image.pixels().map(|pixel| pixel.0[3] >= 128).collect::<Vec<bool>>();
+        let count = mask.iter().filter(|opaque| **opaque).count();
+        if count == 0 {
+            return Err("the template is transparent".into());
+        }
+        let mut pixels = image.pixels().map(|pixel| gray(&image::Rgb([pixel.0[0], pixel.0[1], pixel.0[2]]))).collect::<Vec<f32>>();
+        let mean = pixels.iter().zip(mask.iter()).filter(|(_, opaque)| **opaque).map(|(pixel, _)| pixel).sum::<f32>() / count as f32;
+        for (pixel, opaque) in pixels.iter_mut().zip(mask.iter()) {
+            *pixel = if *opaque { *pixel - mean } else { 0.0 };
+        }
+        let norm = pixels.iter().map(|pixel| pixel * pixel).sum::<f32>().sqrt();
+        Ok(Template {
+            label,
+            width: image.width(),
+            height: image.height(),
+            pixels,
+            opaque: count == mask.len(),
+            mask,
+            count: count as f32,
+            norm,
+            coarse: None,
+        })
+    }
+
+    // Zero mean normalised cross correlation with the window at (x0, y0), over the opaque pixels.
+    // The template has zero mean there, so the window mean only enters through the energy.
+    fn score(&self, window: &Window, x0: u32, y0: u32) -> f32 {
+        let mut correlation = 0.0;
+        let mut sum = 0.0;
+        let mut squares = 0.0;
+        for y in 0..self.height {
+            let row = ((y0 + y) * window.width + x0) as usize;
+            let template_row = (y * self.width) as usize;
+            for x in 0..self.width as usize {
+                let value = window.values[row + x];
+                correlation += value * self.pixels[template_row + x];
+                if !self.opaque && self.mask[template_row + x] {
+                    sum += value as f64;
+                    squares += (value * value) as f64;
+                }
+            }
+        }
+        if self.opaque {
+            (sum, squares) = window.rect_sums(x0, y0, self.width, self.height);
+        }
+        let energy = (squares - sum * sum / self.count as f64) as f32;
+        if energy <= 0.0 || self.norm == 0.0 {
+            return 0.0;
+        }
+        correlation / (energy.sqrt() * self.norm)
+    }
+
+    // Best score of the template around a component. The surroundings of the component are
+    // resized so the component has the template's size, which makes the match independent of
+    // the screen scale, and the template is slid over the margin to absorb box padding.
+    fn match_component(&self, screenshot: &Screenshot, (min_x, min_y, max_x, max_y): Rect) -> f32 {
+        let width = (max_x - min_x + 1) as f32;
+        let height = (max_y - min_y + 1) as f32;
+        let margin_x = (width / 4.0) as usize;
+        let margin_y = (height / 4.0) as usize;
+        let crop_x = min_x.saturating_sub(margin_x);
+        let crop_y = min_y.saturating_sub(margin_y);
+        let crop_width = (max_x + margin_x).min(screenshot.width() as usize - 1) - crop_x + 1;
+        let crop_height = (max_y + margin_y).min(screenshot.height() as usize - 1) - crop_y + 1;
+
+        let scale_x = self.width as f32 / width;
+        let scale_y = self.height as f32 / height;
+        let window_width = ((crop_width as f32 * scale_x).round() as u32).max(self.width);
+        let window_height = ((crop_height as f32 * scale_y).round() as u32).max(self.height);
+
+        let crop = image::imageops::crop_imm(screenshot, crop_x as u32, crop_y as u32, crop_width as u32, crop_height as u32);
+        let window = Window::new(&image::imageops::resize(&*crop, window_width, window_height, FilterType::Triangle));
+
+        let mut best: f32 = 0.0;
+        for y0 in 0..=(window_height - self.height) {
+            for x0 in 0..=(window_width - self.width) {
+                best = best.max(self.score(&window, x0, y0));
+            }
+        }
+        best
+    }
+
+    // Best match inside a component that is larger than the template, e.g. a toolbar the icon
+    // was grouped into, at the template's own size. The peak of the score is only a pixel or two
+    // wide, so instead of a coarse stride the best spots of the half size template on the half
+    // size component are searched in full.
+    fn scan_component(&self, screenshot: &Screenshot, (min_x, min_y, max_x, max_y): Rect) -> (f32, Rect) {
+        let margin_x = self.width as usize / 2;
+        let margin_y = self.height as usize / 2;
+        let crop_x = min_x.saturating_sub(margin_x);
+        let crop_y = min_y.saturating_sub(margin_y);
+        let crop_width = ((max_x + margin_x).min(screenshot.width() as usize - 1) - crop_x + 1) as u32;
+        let crop_height = ((max_y + margin_y).min(screenshot.height() as usize - 1) - crop_y + 1) as u32;
+        if crop_width < self.width || crop_height < self.height {
+            return (0.0, (min_x, min_y, max_x, max_y));
+        }
+        let crop = image::imageops::crop_imm(screenshot, crop_x as u32, crop_y as u32, crop_width, crop_height).to_image();
+        let window = Window::new(&crop);
+
+        let (spots, radius) = match &self.coarse {
+            Some(coarse) if crop_width / 2 >= coarse.width && crop_height / 2 >= coarse.height => {
+                let half = Window::new(&image::imageops::resize(&crop, crop_width / 2, crop_height / 2, FilterType::Triangle));
+                let mut spots = half.offsets(coarse).map(|(x0, y0)| (coarse.score(&half, x0, y0), x0 * 2, y0 * 2)).collect::<Vec<(f32, u32, u32)>>();
+                spots.sort_by(|a, b| b.0.total_cmp(&a.0));
+                spots.truncate(4);
+                (spots.into_iter().map(|(_, x0, y0)| (x0, y0)).collect::<Vec<(u32, u32)>>(), 2)
+            }
+            _ => (window.offsets(self).collect::<Vec<(u32, u32)>>(), 0),
+        };
+
+        let mut best = (0.0, 0, 0);
+        for (spot_x, spot_y) in spots {
+            for y0 in spot_y.saturating_sub(radius)..=(spot_y + radius).min(crop_height - self.height) {
+                for x0 in spot_x.saturating_sub(radius)..=(spot_x + radius).min(crop_width - self.width) {
+                    let score = self.score(&window, x0, y0);
+                    if score > best.0 {
+                        best = (score, x0, y0);
+                    }
+                }
+            }
+        }
+        let (score, x0, y0) = best;
+        (score, (crop_x + x0 as usize, crop_y + y0 as usize, crop_x + (x0 + self.width) as usize - 1, crop_y + (y0 + self.height) as usize - 1))
+    }
+}
+
+// the overlay takes at most 3 typed letters
+const MAX_LABEL_LEN: usize = 3;
+// the first letters of the hint categories (images, lines, boxes, links, media)
+const CATEGORY_LETTERS: [char; 5] = ['a', 'e', 'i', 'o', 'u'];
+
+// Pinned labels are typed without a category letter, so they have to be short enough to type
+// and must not start like a category
+fn check_label(label: &str) -> Result<(), &'static str> {
+    if label.is_empty() || !label.chars().all(|c| c.is_ascii_lowercase()) {
+        return Err("is not lowercase letters");
+    }
+    if label.len() > MAX_LABEL_LEN {
+        return Err("is longer than 3 letters and cannot be typed");
+    }
+    if label.starts_with(CATEGORY_LETTERS) {
+        return Err("starts with a category letter (a, e, i, o, u)");
+    }
+    Ok(())
+}
+
+// Finds user supplied icons (PNGs in the templates directory) and gives them fixed labels
+pub struct TemplateDetector {
+    templates: Vec<Template>,
+    config: config::TemplateConfig,
+}
+
+impl TemplateDetector {
+    pub fn load(templates_config: &config::TemplateConfig) -> TemplateDetector {
+        let mut templates = Vec::new();
+        let dir = match templates_config.dir.clone().or_else(config::templates_dir) {
+            Some(dir) => dir,
+            None => return TemplateDetector { templates, config: templates_config.clone() },
+        };
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => {
+                log::info!("No templates at {:?}", dir);
+                return TemplateDetector { templates, config: templates_config.clone() };
+            }
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().map_or(true, |extension| extension != "png") {
+                continue;
+            }
+            let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+            let label = match templates_config.labels.get(&file_name) {
+                Some(label) => label.clone(),
+                None => path.file_stem().unwrap().to_string_lossy().to_string(),
+            };
+            if let Err(reason) = check_label(&label) {
+                log::warn!("Skipping {:?}, label {:?} {}", path, label, reason);
+                continue;
+            }
+            match Template::load(&path, label) {
+                Ok(template) => {
+                    log::debug!("Loaded {:?} as {:?}", path, template.label);
+                    templates.push(template);
+                }
+                Err(err) => {
+                    log::warn!("Failed to load {:?}: {}", path, err);
+                }
+            }
+        }
+        TemplateDetector { templates, config: templates_config.clone() }
+    }
+}
+
+impl Detector for TemplateDetector {
+    fn name(&self) -> &'static str {
+        "templates"
+    }
+
+    // only the best match per template is kept, across the screen
+    fn whole_screen(&self) -> bool {
+        true
+    }
+
+    fn detect(&self, context: &DetectionContext, _elements: &[Element]) -> Vec<Element> {
+        if self.templates.is_empty() {
+            return Vec::new();
+        }
+
+        let candidates = context.components.par_iter().flat_map_iter(|component| {
+            let mut candidates = Vec::new();
+            if context.cancelled() {
+                return candidates;
+            }
+            let rect = context.unmap(component);
+            let width = (rect.2 - rect.0 + 1) as f32;
+            let height = (rect.3 - rect.1 + 1) as f32;
+            // the best template for a component of about the template's shape
+            let mut best: Option<(usize, f32, Rect)> = None;
+            for (index, template) in self.templates.iter().enumerate() {
+                let scale = width / template.width as f32;
+                let aspect = (width / height) / (template.width as f32 / template.height as f32);
+                if scale >= self.config.min_scale && scale <= self.config.max_scale && aspect >= 0.75 && aspect <= 1.33 {
+                    let score = template.match_component(context.screenshot, rect);
+                    if score >= self.config.threshold && best.map_or(true, |(_, best_score, _)| score > best_score) {
+                        best = Some((index, score, rect));
+                    }
+                } else if width >= template.width as f32 && height >= template.height as f32 && width * height <= (template.width * template.height) as f32 * self.config.scan_area {
+                    let (score, found) = template.scan_component(context.screenshot, rect);
+                    if score >= self.config.threshold {
+                        candidates.push((index, score, found));
+                    }
+                }
+            }
+            candidates.extend(best);
+            candidates
+        }).collect::<Vec<(usize, f32, Rect)>>();
+
+        // A label always means the same target, so only the best match per template is kept, and
+        // where two templates matched the same spot the better one
+        let mut matches = self.templates.iter().enumerate().filter_map(|(index, _)| {
+            candidates.iter().filter(|(template_index, _, _)| *template_index == index).max_by(|a, b| a.1.total_cmp(&b.1))
+        }).collect::<Vec<&(usize, f32, Rect)>>();
+        matches.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let mut elements: Vec<Element> = Vec::new();
+        for (index, score, rect) in matches {
+            if elements.iter().any(|element| overlaps(&element.rect, rect)) {
+                continue;
+            }
+            let template = &self.templates[*index];
+            log::debug!("{:?} matched with {:?}", template.label, score);
+            elements.push(Element::labelled(ElementKind::Pinned, *rect, template.label.clone()));
+        }
+        elements
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a ring on a light background, with transparent corners when masked
+    fn icon(masked: bool) -> image::RgbaImage {
+        image::RgbaImage::from_fn(16, 16, |x, y| {
+            let distance = ((x as f32 - 7.5).powi(2) + (y as f32 - 7.5).powi(2)).sqrt();
+            if distance > 7.5 && masked {
+                image::Rgba([0, 0, 0, 0])
+            } else if distance > 3.0 && distance < 6.0 {
+                image::Rgba([20, 40, 200, 255])
+            } else {
+                image::Rgba([230, 230, 230, 255])
+            }
+        })
+    }
+
+    // the icon drawn at (x, y) on a background that changes from column to column
+    fn screen(icon: &image::RgbaImage, x: u32, y: u32) -> Screenshot {
+        let mut screen = Screenshot::from_fn(200, 40, |x, _| image::Rgb([(x * 7 % 256) as u8, 90, 60]));
+        for (dx, dy, pixel) in icon.enumerate_pixels() {
+            if pixel.0[3] >= 128 {
+                screen.put_pixel(x + dx, y + dy, image::Rgb([pixel.0[0], pixel.0[1], pixel.0[2]]));
+            }
+        }
+        screen
+    }
+
+    #[test]
+    fn integral_score_matches_the_direct_sum() {
+        let template = Template::from_image(&icon(false), "aa".to_string()).unwrap();
+        let window = Window::new(&screen(&icon(false), 30, 10));
+        assert!((template.score(&window, 30, 10) - 1.0).abs() < 1e-3);
+        for (x0, y0) in [(0, 0), (25, 7), (100, 20)] {
+            let values = (0..16).flat_map(|y| (0..16).map(move |x| (x, y))).map(|(x, y)| window.values[((y0 + y) * window.width + x0 + x) as usize]).collect::<Vec<f32>>();
+            let mean = values.iter().sum::<f32>() / values.len() as f32;
+            let correlation = values.iter().zip(template.pixels.iter()).map(|(value, pixel)| (value - mean) * pixel).sum::<f32>();
+            let energy = values.iter().map(|value| (value - mean) * (value - mean)).sum::<f32>();
+            let direct = if energy == 0.0 { 0.0 } else { correlation / (energy.sqrt() * template.norm) };
+            assert!((template.score(&window, x0, y0) - direct).abs() < 1e-3, "at {:?}", (x0, y0));
+        }
+    }
+
+    #[test]
+    fn transparent_pixels_are_masked() {
+        let masked = Template::from_image(&icon(true), "aa".to_string()).unwrap();
+        let opaque = Template::from_image(&icon(false), "aa".to_string()).unwrap();
+        assert!(!masked.opaque && opaque.opaque);
+        // the corners show the background, which only the masked template ignores
+        let window = Window::new(&screen(&icon(true), 30, 10));
+        assert!((masked.score(&window, 30, 10) - 1.0).abs() < 1e-3);
+        assert!(opaque.score(&window, 30, 10) < 0.95);
+        assert!(Template::from_image(&image::RgbaImage::new(4, 4), "aa".to_string()).is_err());
+    }
+
+    #[test]
+    fn scans_inside_larger_components() {
+        let template = Template::from_image(&icon(true), "aa".to_string()).unwrap();
+        let screen = screen(&icon(true), 123, 13);
+        // a toolbar the icon was grouped into
+        let (score, rect) = template.scan_component(&screen, (5, 5, 190, 34));
+        assert!(score > 0.99);
+        assert_eq!(rect, (123, 13, 138, 28));
+    }
+
+    // loads the icon saved under every file name from a fresh directory, returns the labels
+    fn load_labels(test: &str, files: &[&str], labels: &[(&str, &str)]) -> Vec<String> {
+        let dir = std::env::temp_dir().join(format!("swiftmouse-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in files {
+            icon(false).save(dir.join(file)).unwrap();
+        }
+        let templates_config = config::TemplateConfig {
+            dir: Some(dir.clone()),
+            labels: labels.iter().map(|(file, label)| (file.to_string(), label.to_string())).collect(),
+            ..Default::default()
+        };
+        let mut loaded = TemplateDetector::load(&templates_config).templates.into_iter().map(|template| template.label).collect::<Vec<String>>();
+        std::fs::remove_dir_all(&dir).unwrap();
+        loaded.sort();
+        loaded
+    }
+
+    #[test]
+    fn labels_longer_than_three_letters_are_rejected() {
+        assert_eq!(load_labels("long", &["send.png", "rr.png", "run.png"], &[("run.png", "rrun")]), vec!["rr"]);
+        assert_eq!(load_labels("short", &["run.png"], &[("run.png", "rrn")]), vec!["rrn"]);
+    }
+
+    #[test]
+    fn labels_starting_with_a_category_letter_are_rejected() {
+        let files = ["ok.png", "al.png", "ed.png", "in.png", "up.png", "zz.png", "run.png"];
+        assert_eq!(load_labels("category", &files, &[("run.png", "ax")]), vec!["zz"]);
+    }
+}
@@ -0,0 +1,295 @@
+use std::cmp;
+
+use crate::config;
+
+use super::{of_kind, DetectionContext, Detector, Element, ElementKind, Rect};
+
+// Most common component height on the page, which is the body text size. Very flat components
+// (rules, underlines, dots) say nothing about the font size and are ignored.
+fn estimate_text_height(boxes: &Vec<(usize, usize, usize, usize)>, text: &config::TextConfig) -> Option<usize> {
+    let mut histogram: Vec<usize> = Vec::new();
+    let mut samples = 0;
+    for (_, min_y, _, max_y) in boxes {
+        let height = max_y - min_y;
+        if height < 4 {
+            continue;
+        }
+        if histogram.len() <= height {
+            histogram.resize(height + 1, 0);
+        }
+        histogram[height] += 1;
+        samples += 1;
+    }
+    if samples < text.min_samples {
+        return None;
+    }
+
+    let mut body = 0;
+    for height in 0..histogram.len() {
+        if histogram[height] > histogram[body] {
+            body = height;
+        }
+    }
+    Some(body)
+}
+
+// Returns (body text height, max text height)
+pub fn text_heights(boxes: &Vec<(usize, usize, usize, usize)>, text: &config::TextConfig) -> (usize, usize) {
+    match estimate_text_height(boxes, text) {
+        Some(body) => {
+            log::debug!("Body text height: {:?}", body);
+            (body, cmp::max((body as f32 * text.height_ratio).round() as usize, body + 1))
+        }
+        None => (text.fallback_max_height, text.fallback_max_height),
+    }
+}
+
+// Buttons and input fields are outlined, so their top and bottom rows are mostly edges, while
+// the glyphs of a heading only touch them in a few places
+pub fn is_framed(map: &Vec<Vec<bool>>, (min_x, min_y, max_x, max_y): (usize, usize, usize, usize)) -> bool {
+    if max_x - min_x < 3 || max_y - min_y < 3 {
+        return false;
+    }
+    let mut covered = 0;
+    for x in min_x + 1..max_x {
+        if map[x][min_y + 1] {
+            covered += 1;
+        }
+        if map[x][max_y - 1] {
+            covered += 1;
+        }
+    }
+    covered as f32 / (2 * (max_x - min_x - 1)) as f32 > 0.7
+}
+
+// The bottoms and heights of the glyphs are kept sorted, with their medians, since they are
+// looked up for every glyph that may join the line
+struct Line {
+    min_x: usize,
+    min_y: usize,
+    max_x: usize,
+    max_y: usize,
+    bottoms: Vec<usize>,
+    heights: Vec<usize>,
+    // descenders and punctuation are the minority, so the median bottom is the baseline
+    baseline: usize,
+    glyph_height: usize,
+}
+
+fn insert_sorted(values: &mut Vec<usize>, value: usize) -> usize {
+    let index = values.partition_point(|other| *other < value);
+    values.insert(index, value);
+    values[values.len() / 2]
+}
+
+impl Line {
+    fn new((min_x, min_y, max_x, max_y): (usize, usize, usize, usize)) -> Self {
+        Line {
+            min_x,
+            min_y,
+            max_x,
+            max_y,
+            bottoms: vec![max_y],
+            heights: vec![max_y - min_y],
+            baseline: max_y,
+            glyph_height: max_y - min_y,
+        }
+    }
+
+    fn push(&mut self, (min_x, min_y, max_x, max_y): (usize, usize, usize, usize)) {
+        self.min_x = cmp::min(self.min_x, min_x);
+        self.min_y = cmp::min(self.min_y, min_y);
+        self.max_x = cmp::max(self.max_x, max_x);
+        self.max_y = cmp::max(self.max_y, max_y);
+        self.baseline = insert_sorted(&mut self.bottoms, max_y);
+        self.glyph_height = insert_sorted(&mut self.heights, max_y - min_y);
+    }
+}
+
+const ROW_BUCKET: usize = 16;
+
+// The text boxes bucketed by rows of ROW_BUCKET pixels, so the gutter search only looks at the
+// boxes around a gap
+struct Rows<'a> {
+    boxes: &'a [Rect],
+    buckets: Vec<Vec<usize>>,
+}
+
+impl<'a> Rows<'a> {
+    fn new(boxes: &'a [Rect]) -> Self {
+        let max_y = boxes.iter().map(|box_| box_.3).max().unwrap_or(0);
+        let mut buckets = vec![Vec::new(); max_y / ROW_BUCKET + 1];
+        for (index, box_) in boxes.iter().enumerate() {
+            for bucket in buckets[box_.1 / ROW_BUCKET..=box_.3 / ROW_BUCKET].iter_mut() {
+                bucket.push(index);
+            }
+        }
+        Rows { boxes, buckets }
+    }
+
+    // every box in a bucket of the rows from_y to to_y, once
+    fn between(&self, from_y: usize, to_y: usize) -> impl Iterator<Item = &Rect> + '_ {
+        let first = from_y / ROW_BUCKET;
+        let last = cmp::min(to_y / ROW_BUCKET, self.buckets.len() - 1);
+        (first..=last).flat_map(move |bucket| {
+            // a box spanning several buckets is taken from the first one searched
+            self.buckets[bucket].iter().filter(move |index| cmp::max(self.boxes[**index].1 / ROW_BUCKET, first) == bucket).map(move |index| &self.boxes[*index])
+        })
+    }
+}
+
+// A gap is a column gutter if text in the rows above and below ends before it and starts after
+// it, but nothing crosses it
+fn is_gutter(rows: &Rows, gap_start: usize, gap_end: usize, min_y: usize, max_y: usize, search: usize) -> bool {
+    let from_y = min_y.saturating_sub(search);
+    let to_y = max_y + search;
+    let mut left = 0;
+    let mut right = 0;
+    for (bmin_x, bmin_y, bmax_x, bmax_y) in rows.between(from_y, to_y) {
+        if *bmax_y < from_y || *bmin_y > to_y {
+            continue;
+        }
+        // same row
+        if *bmax_y >= min_y && *bmin_y <= max_y {
+            continue;
+        }
+        if *bmin_x <= gap_end && *bmax_x >= gap_start {
+            return false;
+        }
+        if *bmax_x < gap_start {
+            left += 1;
+        } else {
+            right += 1;
+        }
+    }
+    left >= 2 && right >= 2
+}
+
+// Joins glyphs left to right into lines. A glyph joins the line whose baseline band it overlaps
+// most, so descenders, superscripts and mixed font sizes stay on one line, and the allowed gap
+// scales with the glyph size. Nothing depends on reading direction, so right-to-left text is
+// grouped the same way.
+pub fn build_lines(text_boxes: &Vec<(usize, usize, usize, usize)>, lines_config: &config::LineConfig) -> Vec<(usize, usize, usize, usize)> {
+    let mut sorted = text_boxes.clone();
+    sorted.sort_unstable_by_key(|box_| box_.0);
+    let rows = Rows::new(text_boxes);
+
+    let mut lines: Vec<Line> = Vec::new();
+    for box_ in sorted {
+        let (min_x, min_y, _, max_y) = box_;
+        let height = cmp::max(max_y - min_y, 1);
+
+        let mut best: Option<(usize, usize)> = None;
+        for (index, line) in lines.iter().enumerate() {
+            if max_y < line.min_y || min_y > line.max_y {
+                continue;
+            }
+            let glyph_height = line.glyph_height;
+            let size = cmp::max(glyph_height, height);
+            if min_x > line.max_x + (size as f32 * lines_config.gap_ratio) as usize {
+                continue;
+            }
+
+            // core band of the line, from its x-height to its baseline
+            let baseline = line.baseline;
+            let band_top = baseline.saturating_sub(glyph_height);
+            let overlap = cmp::min(max_y, baseline) as i32 - cmp::max(min_y, band_top) as i32;
+            if overlap < (cmp::min(height, glyph_height) / 2) as i32 {
+                continue;
+            }
+
+            if min_x > line.max_x + size / 2 {
+                let search = (size as f32 * lines_config.gutter_rows) as usize;
+                if is_gutter(&rows, line.max_x + 1, min_x - 1, min_y, max_y, search) {
+                    continue;
+                }
+            }
+
+            let distance = (max_y as i32 - baseline as i32).unsigned_abs() as usize;
+            if best.map_or(true, |(_, best_distance)| distance < best_distance) {
+                best = Some((index, distance));
+            }
+        }
+
+        match best {
+            Some((index, _)) => lines[index].push(box_),
+            None => lines.push(Line::new(box_)),
+        }
+    }
+
+    lines.iter().map(|line| (line.min_x, line.min_y, line.max_x, line.max_y)).collect::<Vec<(usize, usize, usize, usize)>>()
+}
+
+// Text lines, glyphs inside media regions (subtitles, video content) are skipped
+pub struct LineDetector;
+
+impl Detector for LineDetector {
+    fn name(&self) -> &'static str {
+        "lines"
+    }
+
+    fn detect(&self, context: &DetectionContext, elements: &[Element]) -> Vec<Element> {
+        let media = of_kind(elements, ElementKind::Media);
+        let text_boxes = context.components.iter().filter(|component| {
+            context.is_text(component)
+        }).filter(|component| {
+            let (min_x, min_y, max_x, max_y) = context.unmap(component);
+            !media.iter().any(|video| {
+                min_x >= video.0 && min_y >= video.1 && max_x <= video.2 && max_y <= video.3
+            })
+        }).map(|component| *component).collect::<Vec<Rect>>();
+
+        build_lines(&text_boxes, &context.config.lines).iter().map(|line| {
+            Element::new(ElementKind::Line, context.unmap(line))
+        }).collect::<Vec<Element>>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a row of glyphs, 10 high and 10 wide
+    fn row(y: usize, xs: &[usize]) -> Vec<Rect> {
+        xs.iter().map(|x| (*x, y, x + 9, y + 10)).collect::<Vec<Rect>>()
+    }
+
+    fn sorted(mut rects: Vec<Rect>) -> Vec<Rect> {
+        rects.sort();
+        rects
+    }
+
+    #[test]
+    fn rows_yield_each_box_once() {
+        let boxes = vec![(0, 0, 5, 40), (0, 20, 5, 25), (0, 100, 5, 110)];
+        let rows = Rows::new(&boxes);
+        assert_eq!(rows.between(10, 50).cloned().collect::<Vec<Rect>>(), vec![(0, 0, 5, 40), (0, 20, 5, 25)]);
+        assert_eq!(sorted(rows.between(0, 1000).cloned().collect::<Vec<Rect>>()), boxes);
+        assert_eq!(rows.between(200, 300).count(), 0);
+    }
+
+    #[test]
+    fn gutters_split_columns() {
+        // the gap between the columns is narrow enough to join a line, but the rows above and
+        // below leave it empty
+        let xs = [0, 12, 24, 36, 54, 66, 78, 90];
+        let boxes = [0, 16, 32, 48, 64].iter().flat_map(|y| row(*y, &xs)).collect::<Vec<Rect>>();
+        let lines = build_lines(&boxes, &config::LineConfig::default());
+        let expected = [0, 16, 32, 48, 64].iter().flat_map(|y| vec![(0, *y, 45, y + 10), (54, *y, 99, y + 10)]).collect::<Vec<Rect>>();
+        assert_eq!(sorted(lines), sorted(expected));
+
+        // a single row has no gutter
+        assert_eq!(build_lines(&row(0, &xs), &config::LineConfig::default()), vec![(0, 0, 99, 10)]);
+    }
+
+    #[test]
+    fn baseline_is_the_median_bottom() {
+        let mut line = Line::new((0, 10, 5, 20));
+        line.push((6, 12, 10, 24));
+        line.push((11, 10, 15, 20));
+        line.push((16, 4, 18, 8));
+        assert_eq!(line.baseline, 20);
+        assert_eq!(line.glyph_height, 10);
+        assert_eq!((line.min_x, line.min_y, line.max_x, line.max_y), (0, 4, 18, 24));
+    }
+}
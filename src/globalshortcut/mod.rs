@@ -1,32 +1,100 @@
+use std::sync::{Arc, Mutex};
+
 use tokio::sync::mpsc;
 use zbus::{blocking::connection, interface};
 
+use crate::browser;
+use crate::trace;
+use crate::detection::CancellationToken;
+
+// what the overlay should hint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Elements,
+    Windows,
+}
+
+// A Run or RunWindows call, with the time it arrived for the latency trace
+#[derive(Debug, Clone, Copy)]
+pub struct Trigger {
+    pub mode: Mode,
+    pub time_us: u64,
+}
+
 struct ZbusListener {
     // tx channel
-    tx: mpsc::Sender<()>,
+    tx: mpsc::Sender<Trigger>,
+    // the detection job in flight, cancelled when a new one is requested
+    job: Arc<Mutex<CancellationToken>>,
 }
 
 #[interface(name = "com.quexten.swiftmouse")]
 impl ZbusListener {
     async fn run(&mut self) -> String {
-        println!("sending");
-        self.tx.send(()).await.unwrap();
+        log::debug!("Run");
+        let time_us = trace::now_us();
+        self.job.lock().unwrap().cancel();
+        self.tx.send(Trigger { mode: Mode::Elements, time_us }).await.unwrap();
+        "".to_string()
+    }
+
+    // window switcher, hints the visible top level windows
+    async fn run_windows(&mut self) -> String {
+        let time_us = trace::now_us();
+        self.job.lock().unwrap().cancel();
+        self.tx.send(Trigger { mode: Mode::Windows, time_us }).await.unwrap();
         "".to_string()
     }
+
+    // called by the overlay when it is closed before detection finished
+    async fn cancel(&mut self) -> String {
+        log::debug!("Cancel");
+        self.job.lock().unwrap().cancel();
+        "".to_string()
+    }
+
+    // the latency breakdown of the last runs as JSON, see trace
+    async fn latency(&self) -> String {
+        trace::to_json()
+    }
+
+    // called by the native messaging host with a JSON array of screen rects, returns how many
+    // elements were stored
+    async fn push_browser_elements(&mut self, elements: String) -> zbus::fdo::Result<u32> {
+        match browser::store_json(&elements) {
+            Ok(len) => {
+                log::debug!("Received {:?} elements", len);
+                Ok(len as u32)
+            }
+            Err(err) => {
+                log::warn!("Invalid elements: {}", err);
+                Err(zbus::fdo::Error::InvalidArgs(err.to_string()))
+            }
+        }
+    }
 }
 
 
 // fn listen and have a return channel to send events
-pub async fn listen() -> (mpsc::Receiver<()> , connection::Connection) {
+// job is where the daemon puts the token of the detection job it is running
+pub async fn listen(job: Arc<Mutex<CancellationToken>>) -> (mpsc::Receiver<Trigger> , connection::Connection) {
     let (tx, rx) = mpsc::channel(1);
     let listener = ZbusListener {
         tx,
+        job,
     };
     let _conn = connection::Builder::session().unwrap()
         .name("com.quexten.swiftmouse").unwrap()
         .serve_at("/com/quexten/swiftmouse", listener).unwrap()
         .build()
         .unwrap();
-    println!("[Global Shortcuts] Listening on D-Bus");
+    log::info!("Listening on D-Bus");
     return (rx, _conn);
-}
\ No newline at end of file
+}
+// Asks the daemon to abort the running detection job
+pub fn request_cancel() -> Result<(), Box<dyn std::error::Error>> {
+    let connection = zbus::blocking::Connection::session()?;
+    let proxy = zbus::blocking::Proxy::new(&connection, "com.quexten.swiftmouse", "/com/quexten/swiftmouse", "com.quexten.swiftmouse")?;
+    let _: String = proxy.call("Cancel", &())?;
+    Ok(())
+}
@@ -1,8 +1,9 @@
-use std::{fmt::format, sync::Arc};
+use std::{fmt::format, sync::{Arc, Mutex}};
 
 use eframe::egui::{self, InputState};
 
 use crate::autotype::{self, ClickType};
+use crate::overlay;
 
 static COLOR_GRAY: egui::Color32 = egui::Color32::from_rgb(100, 100, 100);
 
@@ -15,42 +16,179 @@ const LETTER_LINK: u8 = 20;
 // u
 const LETTER_BIG_BOX: u8 = 4;
 // i
-const LETTER_LINE: u8 = 8;
-
-pub fn show_gui(big_boxes: Vec<(u32, u32, u32, u32)>, line_boxes: Vec<(u32, u32, u32, u32)>, small_images: Vec<(u32, u32, u32, u32)>, large_images: Vec<(u32, u32, u32, u32)>, links: Vec<(u32, u32, u32, u32)>, path: String) {
-    let mut options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default().with_inner_size([400.0, 800.0]),
-        ..Default::default()
-    };
-    options.viewport.fullscreen = Some(true);
-    let _ = eframe::run_native(
-        "Swiftmouse",
-        options,
-        Box::new(|cc| {
-            egui_extras::install_image_loaders(&cc.egui_ctx);
-            let mut app = Box::<MyApp>::default();
-            app.line_boxes = line_boxes;
-            app.big_boxes = big_boxes;
-            app.small_images = small_images;
-            app.large_images = large_images;
-            app.links = links;
-            app.path = path;
-            app.letters_typed = vec![];
-            Ok(app)
-        }),
-    );
+const LETTER_MEDIA: u8 = 8;
+
+// A hint list from the daemon, replacing the list shown so far
+pub enum Batch {
+    BigBoxes(Vec<((u32, u32, u32, u32), u32)>),
+    Lines(Vec<((u32, u32, u32, u32), u32)>),
+    SmallImages(Vec<((u32, u32, u32, u32), u32)>),
+    Media(Vec<((u32, u32, u32, u32), u32)>),
+    Links(Vec<((u32, u32, u32, u32), u32)>),
+    MediaPoints(Vec<((u32, u32, u32, u32), u32)>),
+    Pinned(Vec<((u32, u32, u32, u32), String)>),
+}
+
+// The screenshot of a show command, with the trace of the daemon's run and when the command
+// arrived and the screenshot was read, for the latency trace
+pub struct Show {
+    pub trace: u32,
+    pub received_us: u64,
+    pub loaded_us: u64,
+    pub screenshot: image::ImageBuffer<image::Rgb<u8>, Vec<u8>>,
+}
+
+// A command from the daemon to the resident overlay
+pub enum Command {
+    // shows the screenshot right away, the hints follow as batches
+    ShowElements(Show),
+    Batch(Batch),
+    // all batches were sent
+    Done,
+    // Window switcher: every visible window is hinted with two letters, typed without a
+    // category letter, and focused when selected
+    ShowWindows(Show, Vec<((u32, u32, u32, u32), u32, String)>),
+    Hide,
+}
+
+// What the user chose in the overlay, done once its window is closed so the click or focus goes
+// to what is under it
+enum Action {
+    Click(ClickType),
+    Activate(u32),
+}
+
+impl Action {
+    fn run(self) {
+        tokio::spawn(async move {
+            // give the compositor time to unmap the window
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            match self {
+                Action::Click(click_type) => autotype::click(click_type).await,
+                Action::Activate(id) => {
+                    if let Err(err) = crate::windows::activate(id) {
+                        log::warn!("Failed to activate window: {}", err);
+                    }
+                }
+            }
+        });
+    }
+}
+
+// Runs the overlay until the daemon exits. A window is opened for every show command and closed
+// again when it is hidden, instead of hiding a resident window: Wayland has no way to hide a
+// window, and a hidden window on X11 does not reliably get to handle commands. The event loop
+// and the portal sessions stay, so only the window is created each time.
+pub fn show_overlay(commands: std::sync::mpsc::Receiver<Command>, mut events: std::os::unix::net::UnixStream) {
+    // the context of the open window, woken for every command
+    let window: Arc<Mutex<Option<egui::Context>>> = Arc::new(Mutex::new(None));
+    let (tx, rx) = std::sync::mpsc::channel();
+    let waker = window.clone();
+    std::thread::spawn(move || {
+        for command in commands {
+            if tx.send(command).is_err() {
+                return;
+            }
+            if let Some(ctx) = waker.lock().unwrap().as_ref() {
+                ctx.request_repaint();
+            }
+        }
+        drop(tx);
+        if let Some(ctx) = waker.lock().unwrap().as_ref() {
+            ctx.request_repaint();
+        }
+    });
+    let commands = Arc::new(Mutex::new(rx));
+    loop {
+        // batches and hides that arrive while no window is open belong to one closed already
+        let command = commands.lock().unwrap().recv();
+        let (show, windows) = match command {
+            Ok(Command::ShowElements(show)) => (show, None),
+            Ok(Command::ShowWindows(show, windows)) => (show, Some(windows)),
+            Ok(_) => continue,
+            Err(_) => return,
+        };
+        // While the hints are detected the pointer goes through the overlay, so the cursor
+        // detector reads the cursor of the application under it and not the overlay's. It is
+        // caught again once all hints arrived.
+        let mut options = eframe::NativeOptions {
+            viewport: egui::ViewportBuilder::default().with_inner_size([400.0, 800.0]).with_mouse_passthrough(windows.is_none()),
+            ..Default::default()
+        };
+        options.viewport.fullscreen = Some(true);
+        let action = Arc::new(Mutex::new(None));
+        let app_window = window.clone();
+        let app_commands = commands.clone();
+        let app_events = events.try_clone().ok();
+        let app_action = action.clone();
+        let start = crate::trace::now_us();
+        // returns when the window is closed, the event loop is kept for the next one
+        let result = eframe::run_native(
+            "Swiftmouse",
+            options,
+            Box::new(move |cc| {
+                *app_window.lock().unwrap() = Some(cc.egui_ctx.clone());
+                let mut app = Box::<MyApp>::default();
+                app.commands = Some(app_commands);
+                app.events = app_events;
+                app.action = app_action;
+                app.send_span(show.trace, "overlay_open_window", start, crate::trace::now_us());
+                app.load_screenshot(&cc.egui_ctx, show);
+                match windows {
+                    Some(windows) => {
+                        app.windows = windows;
+                        app.switcher = true;
+                    }
+                    None => app.receiving = true,
+                }
+                Ok(app)
+            }),
+        );
+        *window.lock().unwrap() = None;
+        if let Err(err) = result {
+            log::warn!("Failed to open the overlay: {}", err);
+        }
+        if let Some(action) = action.lock().unwrap().take() {
+            action.run();
+        }
+        if let Err(err) = overlay::write_message(&mut events, overlay::EVENT_HIDDEN, &[], None) {
+            log::warn!("Failed to notify the daemon: {}", err);
+        }
+    }
 }
 
 #[derive(Default)]
 struct MyApp {
-    line_boxes: Vec<(u32, u32, u32, u32)>,
-    big_boxes: Vec<(u32, u32, u32, u32)>,
-    small_images: Vec<(u32, u32, u32, u32)>,
-    large_images: Vec<(u32, u32, u32, u32)>,
-    links: Vec<(u32, u32, u32, u32)>,
+    // boxes with the slot their hint label is made from, slots stay the same across invocations
+    line_boxes: Vec<((u32, u32, u32, u32), u32)>,
+    big_boxes: Vec<((u32, u32, u32, u32), u32)>,
+    small_images: Vec<((u32, u32, u32, u32), u32)>,
+    large_images: Vec<((u32, u32, u32, u32), u32)>,
+    links: Vec<((u32, u32, u32, u32), u32)>,
+    media_points: Vec<((u32, u32, u32, u32), u32)>,
+    pinned: Vec<((u32, u32, u32, u32), String)>,
     letters_typed: Vec<u32>,
     selected_box: Option<(u32, u32, u32, u32)>,
-    path: String,
+    commands: Option<Arc<Mutex<std::sync::mpsc::Receiver<Command>>>>,
+    events: Option<std::os::unix::net::UnixStream>,
+    // done by show_overlay after the window is closed
+    action: Arc<Mutex<Option<Action>>>,
+    // hints still to come from the daemon
+    receiving: bool,
+    // the trace and arrival of the show command, until its first frame is drawn
+    first_frame: Option<(u32, u64)>,
+    // window switcher mode
+    windows: Vec<((u32, u32, u32, u32), u32, String)>,
+    switcher: bool,
+    selected_window: Option<u32>,
+    screenshot: Option<egui::TextureHandle>,
+}
+
+// uploads the raw frame, no image loader or decoding involved
+fn screenshot_texture(ctx: &egui::Context, screenshot: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>) -> egui::TextureHandle {
+    let size = [screenshot.width() as usize, screenshot.height() as usize];
+    let image = egui::ColorImage::from_rgb(size, screenshot.as_raw());
+    ctx.load_texture("screenshot", image, egui::TextureOptions::default())
 }
 
 fn get_key(i: &InputState) -> Option<i32> {
@@ -147,8 +285,8 @@ impl MyApp {
 
         let image_color = egui::Color32::from_rgb(255, 160, 50);
 
-        for (index, (start_x, start_y, end_x, end_y)) in self.small_images.iter().enumerate() {
-            let (letter1, letter2) = get_letters_for_index(index as i32);
+        for ((start_x, start_y, end_x, end_y), slot) in self.small_images.iter() {
+            let (letter1, letter2) = get_letters_for_index(*slot as i32);
             if self.letters_typed.len() > 1 {
                 if self.letters_typed[1] as u8 != letter1 {
                     continue
@@ -194,8 +332,8 @@ impl MyApp {
             return
         }
 
-        for (index, (start_x, start_y, end_x, end_y)) in self.line_boxes.iter().enumerate() {   
-            let (letter1, letter2, letter3) = get_letters_for_index_3(index as i32);
+        for ((start_x, start_y, end_x, end_y), slot) in self.line_boxes.iter() {   
+            let (letter1, letter2, letter3) = get_letters_for_index_3(*slot as i32);
             if self.letters_typed.len() > 1 {
                 if self.letters_typed[1] as u8 != letter1 {
                     continue
@@ -246,8 +384,8 @@ impl MyApp {
             return
         }
 
-        for (index, (start_x, start_y, end_x, end_y)) in self.big_boxes.iter().enumerate() {
-            let (letter1, letter2) = get_letters_for_index(index as i32);
+        for ((start_x, start_y, end_x, end_y), slot) in self.big_boxes.iter() {
+            let (letter1, letter2) = get_letters_for_index(*slot as i32);
             if self.letters_typed.len() > 1 {
                 if self.letters_typed[1] as u8 != letter1 {
                     continue
@@ -293,8 +431,8 @@ impl MyApp {
             return
         }
 
-        for (index, (start_x, start_y, end_x, end_y)) in self.links.iter().enumerate() {
-            let (letter1, letter2) = get_letters_for_index(index as i32);
+        for ((start_x, start_y, end_x, end_y), slot) in self.links.iter() {
+            let (letter1, letter2) = get_letters_for_index(*slot as i32);
             if self.letters_typed.len() > 1 {
                 if self.letters_typed[1] as u8 != letter1 {
                     continue
@@ -331,10 +469,256 @@ impl MyApp {
             // }
         }
     }
+    fn draw_media(&mut self, ui: &mut egui::Ui) {
+        let image_color = egui::Color32::from_rgb(0, 200, 200);
+        let is_other_selected = self.letters_typed.len() > 0 && self.letters_typed[0] as u8 != LETTER_MEDIA;
+        if is_other_selected {
+            return
+        }
+
+        for ((start_x, start_y, end_x, end_y), _) in self.large_images.iter() {
+            ui.painter().rect_stroke(
+                egui::Rect::from_min_max(
+                    egui::pos2(*start_x as f32, *start_y as f32),
+                    egui::pos2(*end_x as f32, *end_y as f32),
+                ),
+                0.0,
+                egui::Stroke::new(2.0, image_color),
+            );
+        }
+
+        for ((start_x, start_y, end_x, end_y), slot) in self.media_points.iter() {
+            let (letter1, letter2) = get_letters_for_index(*slot as i32);
+            if self.letters_typed.len() > 1 {
+                if self.letters_typed[1] as u8 != letter1 {
+                    continue
+                }
+            }
+            if self.letters_typed.len() > 2 {
+                if self.letters_typed[2] as u8 != letter2 {
+                    continue
+                } else {
+                    self.selected_box = Some((*start_x, *start_y, *end_x, *end_y));
+                    continue
+                }
+            }
+            if self.letters_typed.len() == 2 {
+                self.selected_box = None
+            }
+
+            ui.painter().circle_filled(
+                egui::pos2((*start_x + *end_x) as f32 / 2.0, (*start_y + *end_y) as f32 / 2.0),
+                4.0,
+                image_color,
+            );
+            let label = format!("{}{}", std::char::from_u32(letter1 as u32 + 65).unwrap(), std::char::from_u32(letter2 as u32 + 65).unwrap());
+            ui.allocate_ui_at_rect(egui::Rect::from_min_max(
+                egui::pos2(*end_x as f32, *end_y as f32),
+                egui::pos2(*end_x as f32 + 100.0, *end_y as f32 + 100.0),
+            ), |ui| {
+                ui.label(egui::RichText::new(label).heading().color(egui::Color32::from_rgb(255, 255, 255)).background_color(image_color));
+            });
+        }
+    }
+    // pinned elements are typed by their label directly, without a category letter
+    fn draw_pinned(&mut self, ui: &mut egui::Ui) {
+        let image_color = egui::Color32::from_rgb(220, 50, 50);
+        let typed = self.letters_typed.iter().map(|x| std::char::from_u32(*x as u32 + 97).unwrap()).collect::<String>();
+
+        for ((start_x, start_y, end_x, end_y), label) in self.pinned.iter() {
+            if !label.starts_with(&typed) {
+                continue
+            }
+            if !typed.is_empty() && typed == *label {
+                self.selected_box = Some((*start_x, *start_y, *end_x, *end_y));
+                continue
+            }
+
+            ui.painter().rect_stroke(
+                egui::Rect::from_min_max(
+                    egui::pos2(*start_x as f32, *start_y as f32),
+                    egui::pos2(*end_x as f32, *end_y as f32),
+                ),
+                0.0,
+                egui::Stroke::new(2.0, image_color),
+            );
+            ui.allocate_ui_at_rect(egui::Rect::from_min_max(
+                egui::pos2(*start_x as f32, *start_y as f32),
+                egui::pos2(*start_x as f32 + 100.0, *end_y as f32 + 100.0),
+            ), |ui| {
+                ui.label(egui::RichText::new(label.to_uppercase()).heading().color(egui::Color32::from_rgb(255, 255, 255)).background_color(image_color));
+            });
+        }
+    }
+
+    fn draw_windows(&mut self, ui: &mut egui::Ui) {
+        let image_color = egui::Color32::from_rgb(50, 180, 80);
+
+        for (index, ((start_x, start_y, end_x, end_y), id, title)) in self.windows.iter().enumerate() {
+            let (letter1, letter2) = get_letters_for_index(index as i32);
+            if self.letters_typed.len() > 0 && self.letters_typed[0] as u8 != letter1 {
+                continue
+            }
+            if self.letters_typed.len() > 1 {
+                if self.letters_typed[1] as u8 != letter2 {
+                    continue
+                }
+                self.selected_window = Some(*id);
+                continue
+            }
+
+            ui.painter().rect_stroke(
+                egui::Rect::from_min_max(
+                    egui::pos2(*start_x as f32, *start_y as f32),
+                    egui::pos2(*end_x as f32, *end_y as f32),
+                ),
+                0.0,
+                egui::Stroke::new(4.0, image_color),
+            );
+            let label = format!("{}{} {}", std::char::from_u32(letter1 as u32 + 65).unwrap(), std::char::from_u32(letter2 as u32 + 65).unwrap(), title);
+            let center = egui::pos2((*start_x + *end_x) as f32 / 2.0, (*start_y + *end_y) as f32 / 2.0);
+            ui.allocate_ui_at_rect(egui::Rect::from_min_max(
+                egui::pos2(center.x - 150.0, center.y - 30.0),
+                egui::pos2(center.x + 150.0, center.y + 30.0),
+            ), |ui| {
+                ui.label(egui::RichText::new(label).heading().color(egui::Color32::from_rgb(255, 255, 255)).background_color(image_color).size(30.0));
+            });
+        }
+    }
+
+    fn update_switcher(&mut self, ctx: &egui::Context) {
+        let mut close = false;
+        ctx.input(|i| {
+            if i.key_released(egui::Key::Escape) {
+                close = true;
+            }
+            if i.key_released(egui::Key::Backspace) {
+                self.letters_typed.pop();
+            }
+            if let Some(key) = get_key(i) {
+                if self.letters_typed.len() < 2 {
+                    self.letters_typed.push(key as u32);
+                }
+            }
+        });
+        if close {
+            self.close(ctx, None);
+            return;
+        }
+
+        let frame = egui::Frame::default().fill(egui::Color32::from_rgb(0, 0, 0)).inner_margin(0.0);
+        egui::CentralPanel::default().frame(frame).show(ctx, |ui| {
+            if let Some(screenshot) = &self.screenshot {
+                ui.add(
+                    egui::Image::from_texture(egui::load::SizedTexture::from_handle(screenshot))
+                );
+            }
+            self.draw_windows(ui);
+        });
+
+        // the overlay has to be gone before the window manager is asked to focus another window
+        if let Some(id) = self.selected_window.take() {
+            self.close(ctx, Some(Action::Activate(id)));
+        }
+        self.report_first_frame();
+    }
+}
+
+impl MyApp {
+    fn receive_commands(&mut self, ctx: &egui::Context) {
+        loop {
+            let command = match self.commands.as_ref().map(|commands| commands.lock().unwrap().try_recv()) {
+                Some(Ok(command)) => command,
+                Some(Err(std::sync::mpsc::TryRecvError::Empty)) | None => break,
+                Some(Err(std::sync::mpsc::TryRecvError::Disconnected)) => {
+                    log::warn!("Daemon exited");
+                    ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                    return;
+                }
+            };
+            match command {
+                // a show while the window is still open, when the daemon did not wait for it to close
+                Command::ShowElements(show) => {
+                    self.reset();
+                    self.load_screenshot(ctx, show);
+                    self.receiving = true;
+                    ctx.send_viewport_cmd(egui::ViewportCommand::MousePassthrough(true));
+                }
+                Command::Batch(Batch::BigBoxes(boxes)) => self.big_boxes = boxes,
+                Command::Batch(Batch::Lines(boxes)) => self.line_boxes = boxes,
+                Command::Batch(Batch::SmallImages(boxes)) => self.small_images = boxes,
+                Command::Batch(Batch::Media(boxes)) => self.large_images = boxes,
+                Command::Batch(Batch::Links(boxes)) => self.links = boxes,
+                Command::Batch(Batch::MediaPoints(boxes)) => self.media_points = boxes,
+                Command::Batch(Batch::Pinned(boxes)) => self.pinned = boxes,
+                Command::Done => {
+                    self.receiving = false;
+                    ctx.send_viewport_cmd(egui::ViewportCommand::MousePassthrough(false));
+                }
+                Command::ShowWindows(show, windows) => {
+                    self.reset();
+                    ctx.send_viewport_cmd(egui::ViewportCommand::MousePassthrough(false));
+                    self.load_screenshot(ctx, show);
+                    self.windows = windows;
+                    self.switcher = true;
+                }
+                Command::Hide => self.close(ctx, None),
+            }
+        }
+    }
+
+    fn load_screenshot(&mut self, ctx: &egui::Context, show: Show) {
+        self.send_span(show.trace, "overlay_read_frame", show.received_us, show.loaded_us);
+        let start = crate::trace::now_us();
+        self.screenshot = Some(screenshot_texture(ctx, &show.screenshot));
+        self.send_span(show.trace, "overlay_texture", start, crate::trace::now_us());
+        self.first_frame = Some((show.trace, show.received_us));
+    }
+
+    // called at the end of update, the frame is presented right after
+    fn report_first_frame(&mut self) {
+        if let Some((trace, received_us)) = self.first_frame.take() {
+            self.send_span(trace, "overlay_first_frame", received_us, crate::trace::now_us());
+        }
+    }
+
+    fn send_span(&mut self, trace: u32, stage: &str, start_us: u64, end_us: u64) {
+        let events = match self.events.as_mut() {
+            Some(events) => events,
+            None => return,
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        overlay::write_span(&mut buffer, trace, stage, start_us, end_us);
+        if let Err(err) = overlay::write_message(events, overlay::EVENT_SPAN, &buffer, None) {
+            log::warn!("Failed to send a trace span: {}", err);
+        }
+    }
+
+    // clears what the previous invocation showed
+    fn reset(&mut self) {
+        let commands = self.commands.take();
+        let events = self.events.take();
+        let action = self.action.clone();
+        *self = MyApp::default();
+        self.commands = commands;
+        self.events = events;
+        self.action = action;
+    }
+
+    // closes the window, show_overlay does the action and tells the daemon
+    fn close(&mut self, ctx: &egui::Context, action: Option<Action>) {
+        *self.action.lock().unwrap() = action;
+        ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+    }
 }
 
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.receive_commands(ctx);
+        if self.switcher {
+            self.update_switcher(ctx);
+            return;
+        }
         let mut key_to_click:Option<ClickType> = None;
         let mut close = false;
         let mut width = 0;
@@ -370,7 +754,7 @@ impl eframe::App for MyApp {
             let key = get_key(i);
             match key {
                 Some(key) => {
-                    println!("Key pressed: {:?}", key);
+                    log::debug!("Key pressed: {:?}", key);
                     // max len 3
                     if self.letters_typed.len() < 3 || (self.letters_typed[0] == LETTER_TEXT as u32 && self.letters_typed.len() < 4) {
                         self.letters_typed.append(&mut vec![key as u32]);
@@ -382,18 +766,17 @@ impl eframe::App for MyApp {
 
         let frame = egui::Frame::default().fill(egui::Color32::from_rgb(0, 0, 0)).inner_margin(0.0);
         egui::CentralPanel::default().frame(frame).show(ctx, |ui| {
-            if key_to_click.is_some() {
-                ctx.send_viewport_cmd(egui::ViewportCommand::Minimized(true));
-                let click_type = key_to_click.unwrap();
-                tokio::spawn(async move {
-                    // sleep
-                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
-                    autotype::click(click_type).await;
-                    std::process::exit(0);
-                });
+            if let Some(click_type) = key_to_click {
+                self.close(ctx, Some(Action::Click(click_type)));
             }
             if close {
-                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                // the hints are not needed anymore, stop the daemon from detecting them
+                if self.receiving {
+                    if let Err(err) = crate::globalshortcut::request_cancel() {
+                        log::warn!("Failed to cancel detection: {}", err);
+                    }
+                }
+                self.close(ctx, None);
             }
 
         //     if self.letters_typed.len() == 2 {
@@ -416,14 +799,18 @@ impl eframe::App for MyApp {
     
         //     }
 
-            ui.add(
-                egui::Image::new("file://".to_owned() + &self.path)
-            );
+            if let Some(screenshot) = &self.screenshot {
+                ui.add(
+                    egui::Image::from_texture(egui::load::SizedTexture::from_handle(screenshot))
+                );
+            }
 
             self.draw_text_lines(ui);
             self.draw_big_boxes(ui);
             self.draw_images(ui);
             self.draw_links(ui);
+            self.draw_media(ui);
+            self.draw_pinned(ui);
             if self.selected_box.is_some() {
                 let (min_x, min_y, max_x, max_y) = self.selected_box.unwrap();
                 
@@ -466,8 +853,8 @@ impl eframe::App for MyApp {
                 // color by letter
                 let color = if self.letters_typed[0] == LETTER_IMAGE as u32{
                     egui::Color32::from_rgb(255, 160, 50)
-                } else if self.letters_typed[0] == LETTER_LINE as u32 {
-                    egui::Color32::from_rgb(150, 200, 20)
+                } else if self.letters_typed[0] == LETTER_MEDIA as u32 {
+                    egui::Color32::from_rgb(0, 200, 200)
                 } else if self.letters_typed[0] == LETTER_BIG_BOX as u32 {
                     egui::Color32::from_rgb(150, 0, 150)
                 } else if self.letters_typed[0] == LETTER_LINK as u32 {
@@ -480,5 +867,6 @@ impl eframe::App for MyApp {
             });
         //     }
      });
+        self.report_first_frame();
     }
 }
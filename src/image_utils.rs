@@ -18,4 +18,29 @@ pub fn draw_boxes(image: &mut DynamicImage, boxes: &[(usize, usize, usize, usize
     for (min_x, min_y, max_x, max_y) in boxes {
         draw_box(image, *min_x, *min_y, *max_x, *max_y, color);
     }
-}
\ No newline at end of file
+}
+// Fraction of pixels that changed between two frames of the same size, sampled every 4th pixel
+pub fn frame_difference(a: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, b: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>) -> f32 {
+    use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+    if a.dimensions() != b.dimensions() {
+        return 1.0;
+    }
+    let (width, height) = a.dimensions();
+    let rows = (0..height).step_by(4).collect::<Vec<u32>>();
+    let (changed, samples) = rows.into_par_iter().map(|y| {
+        let mut changed = 0u32;
+        let mut samples = 0u32;
+        for x in (0..width).step_by(4) {
+            let pixel = a.get_pixel(x, y);
+            let before = b.get_pixel(x, y);
+            let diff = (0..3).map(|c| (pixel[c] as i32 - before[c] as i32).abs()).sum::<i32>();
+            if diff > 24 {
+                changed += 1;
+            }
+            samples += 1;
+        }
+        (changed, samples)
+    }).reduce(|| (0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+    changed as f32 / samples.max(1) as f32
+}
@@ -1,5 +1,14 @@
+pub mod accessibility;
 pub mod autotype;
+pub mod browser;
+pub mod config;
+pub mod detection;
 pub mod gui;
 pub mod image_utils;
+pub mod logging;
+pub mod overlay;
 pub mod globalshortcut;
-pub mod screenshot;
\ No newline at end of file
+pub mod screenshot;
+pub mod trace;
+pub mod tracker;
+pub mod windows;
\ No newline at end of file
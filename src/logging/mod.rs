@@ -0,0 +1,66 @@
+use std::sync::RwLock;
+
+use crate::config;
+
+// overrides the level from the config file, in the same syntax
+const ENV_VAR: &str = "SWIFTMOUSE_LOG";
+// until the config file is read
+const DEFAULT_LEVEL: &str = "warn";
+
+// Filters records by level and module (the target of a record is its module path) and writes
+// them to stderr, or to the systemd journal
+struct Logger {
+    filter: RwLock<Option<env_filter::Filter>>,
+    journal: RwLock<Option<systemd_journal_logger::JournalLog>>,
+}
+
+static LOGGER: Logger = Logger { filter: RwLock::new(None), journal: RwLock::new(None) };
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.filter.read().unwrap().as_ref().map_or(false, |filter| filter.enabled(metadata))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.filter.read().unwrap().as_ref().map_or(false, |filter| filter.matches(record)) {
+            return;
+        }
+        if let Some(journal) = self.journal.read().unwrap().as_ref() {
+            journal.log(record);
+            return;
+        }
+        eprintln!("{} {}: {}", record.level(), record.target(), record.args());
+    }
+
+    fn flush(&self) {
+        if let Some(journal) = self.journal.read().unwrap().as_ref() {
+            journal.flush();
+        }
+    }
+}
+
+// Logs warnings to stderr, or what SWIFTMOUSE_LOG asks for, until configure is called with the
+// config file
+pub fn init() {
+    set_filter(&std::env::var(ENV_VAR).unwrap_or_else(|_| DEFAULT_LEVEL.to_string()));
+    let _ = log::set_logger(&LOGGER);
+}
+
+// Applies the logging section of the config file, SWIFTMOUSE_LOG still takes precedence
+pub fn configure(logging_config: &config::LoggingConfig) {
+    if std::env::var(ENV_VAR).is_err() {
+        set_filter(&logging_config.level);
+    }
+    if logging_config.journald {
+        match systemd_journal_logger::JournalLog::new() {
+            Ok(journal) => *LOGGER.journal.write().unwrap() = Some(journal),
+            Err(err) => log::warn!("Failed to connect to the journal: {}", err),
+        }
+    }
+}
+
+fn set_filter(spec: &str) {
+    let filter = env_filter::Builder::new().parse(spec).build();
+    log::set_max_level(filter.filter());
+    *LOGGER.filter.write().unwrap() = Some(filter);
+}
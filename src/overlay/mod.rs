@@ -0,0 +1,211 @@
+use std::io::{Read, Write};
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+use sendfd::{RecvWithFd, SendWithFd};
+use zbus::zvariant::{Endian, ReadBytes, WriteBytes};
+
+use crate::detection::Element;
+
+// Messages between the daemon and the overlay, on the socket that is the overlay's stdin. Every
+// message is a tag, the length of its payload and the payload, so a reader that does not expect
+// a message skips it without losing its place in the stream. The screenshot memfd travels with
+// its show message as SCM_RIGHTS.
+
+// daemon to overlay: trace id, width and height, with the memfd. The hints follow as batches.
+pub const SHOW_ELEMENTS: u32 = 0;
+// trace id, width and height, then the windows, with the memfd
+pub const SHOW_WINDOWS: u32 = 1;
+pub const HIDE: u32 = 2;
+// Hint lists, each replaces the list shown so far. BATCH_DONE ends the hints of a show, also
+// when detection was cancelled.
+pub const BATCH_BIG_BOXES: u32 = 10;
+pub const BATCH_LINES: u32 = 11;
+pub const BATCH_SMALL_IMAGES: u32 = 12;
+pub const BATCH_MEDIA: u32 = 13;
+pub const BATCH_LINKS: u32 = 14;
+pub const BATCH_MEDIA_POINTS: u32 = 15;
+pub const BATCH_PINNED: u32 = 16;
+pub const BATCH_DONE: u32 = 17;
+
+// overlay to daemon: the overlay is no longer shown
+pub const EVENT_HIDDEN: u32 = 100;
+// a stage of the overlay for the run's trace: trace id, stage name, start and end
+pub const EVENT_SPAN: u32 = 101;
+
+// larger payloads are treated as a broken stream
+const MAX_PAYLOAD_LEN: u32 = 64 * 1024 * 1024;
+
+pub struct Message {
+    pub tag: u32,
+    pub payload: std::io::Cursor<Vec<u8>>,
+    pub fd: Option<OwnedFd>,
+}
+
+pub fn write_message(stream: &mut UnixStream, tag: u32, payload: &[u8], fd: Option<RawFd>) -> std::io::Result<()> {
+    let mut buffer: Vec<u8> = Vec::with_capacity(payload.len() + 8);
+    buffer.write_u32(Endian::Little, tag)?;
+    buffer.write_u32(Endian::Little, payload.len() as u32)?;
+    buffer.write_all(payload)?;
+    let sent = match fd {
+        // the descriptor is attached to the first bytes of the message
+        Some(fd) => stream.send_with_fd(&buffer, &[fd])?,
+        None => 0,
+    };
+    stream.write_all(&buffer[sent..])?;
+    stream.flush()
+}
+
+pub fn read_message(stream: &mut UnixStream) -> std::io::Result<Message> {
+    let mut fds = Vec::new();
+    let mut header = [0u8; 8];
+    read_exact_with_fds(stream, &mut header, &mut fds)?;
+    let mut header = std::io::Cursor::new(header);
+    let tag = header.read_u32(Endian::Little)?;
+    let len = header.read_u32(Endian::Little)?;
+    if len > MAX_PAYLOAD_LEN {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("message of {} bytes", len)));
+    }
+    let mut payload = vec![0u8; len as usize];
+    read_exact_with_fds(stream, &mut payload, &mut fds)?;
+    // only show messages carry a descriptor, any other is closed when dropped
+    let fd = fds.pop();
+    Ok(Message { tag, payload: std::io::Cursor::new(payload), fd })
+}
+
+// Like read_exact, but keeps the descriptors that arrive with the bytes
+fn read_exact_with_fds(stream: &mut UnixStream, buffer: &mut [u8], fds: &mut Vec<OwnedFd>) -> std::io::Result<()> {
+    let mut read = 0;
+    while read < buffer.len() {
+        let mut received = [-1 as RawFd; 4];
+        let (len, fds_len) = stream.recv_with_fd(&mut buffer[read..], &mut received)?;
+        for fd in &received[..fds_len] {
+            fds.push(unsafe { OwnedFd::from_raw_fd(*fd) });
+        }
+        if len == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        read += len;
+    }
+    Ok(())
+}
+
+// Payload of a show message. The screenshot itself is the memfd sent along.
+pub fn write_show(buffer: &mut Vec<u8>, trace_id: u32, width: u32, height: u32) {
+    buffer.write_u32(Endian::Little, trace_id).unwrap();
+    buffer.write_u32(Endian::Little, width).unwrap();
+    buffer.write_u32(Endian::Little, height).unwrap();
+}
+
+pub fn read_show(payload: &mut impl Read) -> std::io::Result<(u32, u32, u32)> {
+    let trace_id = payload.read_u32(Endian::Little)?;
+    let width = payload.read_u32(Endian::Little)?;
+    let height = payload.read_u32(Endian::Little)?;
+    Ok((trace_id, width, height))
+}
+
+// every box is followed by the slot its hint label is made from
+pub fn write_boxes(buffer: &mut Vec<u8>, boxes: &Vec<(usize, usize, usize, usize)>, slots: &Vec<u32>) {
+    buffer.write_u32(Endian::Little, boxes.len() as u32).unwrap();
+    for (box_, slot) in boxes.iter().zip(slots) {
+        buffer.write_u32(Endian::Little, box_.0 as u32).unwrap();
+        buffer.write_u32(Endian::Little, box_.1 as u32).unwrap();
+        buffer.write_u32(Endian::Little, box_.2 as u32).unwrap();
+        buffer.write_u32(Endian::Little, box_.3 as u32).unwrap();
+        buffer.write_u32(Endian::Little, *slot).unwrap();
+    }
+}
+
+pub fn read_boxes(payload: &mut impl Read) -> std::io::Result<Vec<((u32, u32, u32, u32), u32)>> {
+    let boxes_len = payload.read_u32(Endian::Little)?;
+    let mut boxes = Vec::new();
+    for _ in 0..boxes_len {
+        let x = payload.read_u32(Endian::Little)?;
+        let y = payload.read_u32(Endian::Little)?;
+        let width = payload.read_u32(Endian::Little)?;
+        let height = payload.read_u32(Endian::Little)?;
+        let slot = payload.read_u32(Endian::Little)?;
+        boxes.push(((x, y, width, height), slot));
+    }
+    Ok(boxes)
+}
+
+pub fn write_labelled_boxes(buffer: &mut Vec<u8>, elements: &Vec<Element>) {
+    buffer.write_u32(Endian::Little, elements.len() as u32).unwrap();
+    for element in elements {
+        buffer.write_u32(Endian::Little, element.rect.0 as u32).unwrap();
+        buffer.write_u32(Endian::Little, element.rect.1 as u32).unwrap();
+        buffer.write_u32(Endian::Little, element.rect.2 as u32).unwrap();
+        buffer.write_u32(Endian::Little, element.rect.3 as u32).unwrap();
+        let label = element.label.clone().unwrap_or_default();
+        buffer.write_u32(Endian::Little, label.len() as u32).unwrap();
+        buffer.write_all(label.as_bytes()).unwrap();
+    }
+}
+
+pub fn read_labelled_boxes(payload: &mut impl Read) -> std::io::Result<Vec<((u32, u32, u32, u32), String)>> {
+    let boxes_len = payload.read_u32(Endian::Little)?;
+    let mut boxes = Vec::new();
+    for _ in 0..boxes_len {
+        let x = payload.read_u32(Endian::Little)?;
+        let y = payload.read_u32(Endian::Little)?;
+        let width = payload.read_u32(Endian::Little)?;
+        let height = payload.read_u32(Endian::Little)?;
+        boxes.push(((x, y, width, height), read_string(payload)?));
+    }
+    Ok(boxes)
+}
+
+// (rect, window id, title)
+pub fn write_windows(buffer: &mut Vec<u8>, windows: &Vec<((usize, usize, usize, usize), u32, String)>) {
+    buffer.write_u32(Endian::Little, windows.len() as u32).unwrap();
+    for (rect, id, title) in windows {
+        buffer.write_u32(Endian::Little, rect.0 as u32).unwrap();
+        buffer.write_u32(Endian::Little, rect.1 as u32).unwrap();
+        buffer.write_u32(Endian::Little, rect.2 as u32).unwrap();
+        buffer.write_u32(Endian::Little, rect.3 as u32).unwrap();
+        buffer.write_u32(Endian::Little, *id).unwrap();
+        buffer.write_u32(Endian::Little, title.len() as u32).unwrap();
+        buffer.write_all(title.as_bytes()).unwrap();
+    }
+}
+
+pub fn read_windows(payload: &mut impl Read) -> std::io::Result<Vec<((u32, u32, u32, u32), u32, String)>> {
+    let windows_len = payload.read_u32(Endian::Little)?;
+    let mut windows = Vec::new();
+    for _ in 0..windows_len {
+        let x = payload.read_u32(Endian::Little)?;
+        let y = payload.read_u32(Endian::Little)?;
+        let width = payload.read_u32(Endian::Little)?;
+        let height = payload.read_u32(Endian::Little)?;
+        let id = payload.read_u32(Endian::Little)?;
+        windows.push(((x, y, width, height), id, read_string(payload)?));
+    }
+    Ok(windows)
+}
+
+pub fn write_span(buffer: &mut Vec<u8>, trace_id: u32, stage: &str, start_us: u64, end_us: u64) {
+    buffer.write_u32(Endian::Little, trace_id).unwrap();
+    buffer.write_u32(Endian::Little, stage.len() as u32).unwrap();
+    buffer.write_all(stage.as_bytes()).unwrap();
+    buffer.write_u64(Endian::Little, start_us).unwrap();
+    buffer.write_u64(Endian::Little, end_us).unwrap();
+}
+
+pub fn read_span(payload: &mut impl Read) -> std::io::Result<(u32, String, u64, u64)> {
+    let trace_id = payload.read_u32(Endian::Little)?;
+    let stage = read_string(payload)?;
+    let start_us = payload.read_u64(Endian::Little)?;
+    let end_us = payload.read_u64(Endian::Little)?;
+    Ok((trace_id, stage, start_us, end_us))
+}
+
+fn read_string(payload: &mut impl Read) -> std::io::Result<String> {
+    let len = payload.read_u32(Endian::Little)?;
+    if len > MAX_PAYLOAD_LEN {
+        return Err(std::io::ErrorKind::InvalidData.into());
+    }
+    let mut bytes = vec![0u8; len as usize];
+    payload.read_exact(&mut bytes)?;
+    Ok(String::from_utf8_lossy(&bytes).to_string())
+}
@@ -1,4 +1,7 @@
 use std::{borrow::{Borrow, BorrowMut}, fmt::Error, sync::Arc, thread, time::Duration};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
 
 use ashpd::desktop::{print, screenshot};
 use scap::{
@@ -8,47 +11,102 @@ use scap::{
 use tokio::{net::unix::pipe::{self, pipe}, sync::Mutex, time::timeout};
 use xcap::Monitor;
 
+use crate::detection::edges::{self, LumaPlane};
+
+// A captured frame. Frames from the PipeWire stream come with the luma plane the detector
+// needs, converted in the same pass as the image.
+pub struct Capture {
+    pub image: image::ImageBuffer<image::Rgb<u8>, Vec<u8>>,
+    pub luma: Option<LumaPlane>,
+}
+
+impl Capture {
+    fn from_image(image: image::ImageBuffer<image::Rgb<u8>, Vec<u8>>) -> Self {
+        Capture { image, luma: None }
+    }
+}
+
+// Hands a frame to the overlay without encoding it. The RGB pixels are written to a memfd that
+// is then sealed, so the overlay can trust its size and contents. The fd is passed to the
+// overlay as SCM_RIGHTS along with the show message.
+pub struct SharedFrame {
+    memfd: memfd::Memfd,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl SharedFrame {
+    pub fn new(image: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>) -> Result<Self, Box<dyn std::error::Error>> {
+        let memfd = memfd::MemfdOptions::default()
+            .allow_sealing(true)
+            .create("swiftmouse-screenshot")?;
+        memfd.as_file().write_all(image.as_raw())?;
+        memfd.add_seal(memfd::FileSeal::SealShrink)?;
+        memfd.add_seal(memfd::FileSeal::SealGrow)?;
+        memfd.add_seal(memfd::FileSeal::SealWrite)?;
+        memfd.add_seal(memfd::FileSeal::SealSeal)?;
+        Ok(SharedFrame { memfd, width: image.width(), height: image.height() })
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.memfd.as_raw_fd()
+    }
+}
+
+// Reads a frame shared by the daemon, the memfd was passed along with the show message
+pub fn read_shared_frame(file: std::fs::File, width: u32, height: u32) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, Box<dyn std::error::Error>> {
+    let memfd = memfd::Memfd::try_from_file(file).map_err(|_| Error)?;
+    let seals = memfd.seals()?;
+    if !seals.contains(&memfd::FileSeal::SealWrite) || !seals.contains(&memfd::FileSeal::SealShrink) {
+        log::warn!("Shared frame is not sealed");
+        return Err(Box::new(Error));
+    }
+    let mut data = vec![0u8; width as usize * height as usize * 3];
+    memfd.as_file().read_exact_at(&mut data, 0)?;
+    Ok(image::ImageBuffer::from_raw(width, height, data).ok_or(Error)?)
+}
+
 pub struct PipewireCapturer {
     needs_screenshot: Arc<Mutex<bool>>,
-    image_rx: Arc<Mutex<tokio::sync::mpsc::Receiver<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>>>>,
+    image_rx: Arc<Mutex<tokio::sync::mpsc::Receiver<Capture>>>,
 }
 
 impl PipewireCapturer {
-    pub async fn take_screenshot(&mut self) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, Box<dyn std::error::Error>> {
+    pub async fn take_capture(&mut self) -> Result<Capture, Box<dyn std::error::Error>> {
         let start = std::time::Instant::now();
         let mut needs_capture = self.needs_screenshot.lock().await;
-        println!("[PipewireCapturer] Elapsed lock: {:?}", start.elapsed());
+        log::trace!("Elapsed lock: {:?}", start.elapsed());
         *needs_capture = true;
         drop(needs_capture);
 
         if self.image_rx.clone().lock().await.is_closed() {
-            println!("[PipewireCapturer] Image rx is closed");
+            log::warn!("Image rx is closed");
             return Err(Box::new(Error));
         }
 
         let screenshot = timeout(Duration::from_secs(1), self.image_rx.clone().lock().await.recv()).await;
-        println!("[PipewireCapturer] Elapsed rx: {:?}", start.elapsed());
+        log::trace!("Elapsed rx: {:?}", start.elapsed());
         match screenshot {
             Ok(res) => {
                 match res {
                     Some(screenshot) => {
-                        println!("[PipewireCapturer] Screenshot taken");
+                        log::trace!("Screenshot taken");
                         return Ok(screenshot);
                     }
                     None => {
-                        println!("[PipewireCapturer] Failed to take screenshot");
+                        log::warn!("Failed to take screenshot");
                         return Err(Box::new(Error));
                     }
                 }
             }
             Err(_) => {
-                println!("[PipewireCapturer] Timeout");
+                log::warn!("Timed out waiting for a frame");
                 let image_rx = self.image_rx.clone();
                 tokio::spawn(async move {
-                    println!("[PipewireCapturer] Cleanup job started");
+                    log::debug!("Cleanup job started");
                     let img_rx = image_rx.clone();
                     img_rx.lock().await.recv().await;
-                    println!("[PipewireCapturer] Cleanup job done ");
+                    log::debug!("Cleanup job done");
                 });
                 return Err(Box::new(Error));
             }
@@ -84,59 +142,92 @@ impl ScreenshotTool {
                                 Ok(_) => {
                                 }
                                 Err(_) => {
-                                    println!("[Watchdog] timeout");
+                                    log::trace!("Watchdog timeout");
                                     close = true;
                                 }
                             }
                         }
                     }
                     None => {
-                        println!("[Watchdog] No capturer found");
+                        log::warn!("Watchdog found no capturer");
                         tokio::time::sleep(Duration::from_secs(1)).await;
                     }
                 }
                 if close {
-                    println!("[Watchdog] Capturer closing");
+                    log::trace!("Watchdog closing the capturer");
                     *timeout_rx_opt = None;
                     drop(timeout_rx_opt);
                     let mut pipewire_capturer = pipewire_capture.lock().await;
                     *pipewire_capturer = None;
                     *closed.lock().await = true;
-                    println!("[Watchdog] Capturer closed");
+                    log::trace!("Watchdog closed the capturer");
                 }
             }
         });
     }
 
     pub async fn take_screenshot(&mut self) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, Box<dyn std::error::Error>> {
-        match self.take_screenshot_pipewire().await {
-            Ok(screenshot) => {
-                println!("[Screenshot Tool] Screenshot taken using pipewire");
-                return Ok(screenshot);
+        Ok(self.take_capture().await?.image)
+    }
+
+    pub async fn take_capture(&mut self) -> Result<Capture, Box<dyn std::error::Error>> {
+        match self.take_capture_pipewire().await {
+            Ok(capture) => {
+                log::debug!("Screenshot taken using pipewire");
+                return Ok(capture);
             }
             Err(err) => {
-                println!("[Screenshot Tool] Failed to take screenshot using pipewire: {:?}", err);
-                println!("[Screenshot Tool] Failed to take screenshot using pipewire, falling back to screenshot portal");
+                log::warn!("Failed to take screenshot using pipewire: {:?}", err);
+                log::warn!("Failed to take screenshot using pipewire, falling back to screenshot portal");
                 match screenshot_portal().await {
                     Ok(screenshot) => {
                         let screenshot = image::open(screenshot)?.to_rgb8();
-                        return Ok(screenshot);
+                        return Ok(Capture::from_image(screenshot));
                     }
                     Err(_) => {
-                        println!("[Screenshot Tool] Failed to take screenshot using screenshot portal");
+                        log::warn!("Failed to take screenshot using screenshot portal");
                         let screenshot = screenshot_xcap().await?;
                         let screenshot = image::open(screenshot)?.to_rgb8();
-                        return Ok(screenshot);
+                        return Ok(Capture::from_image(screenshot));
                     }
                 }
             }
         }
     }
 
+    // Takes frames from the PipeWire stream until two consecutive frames differ by less than
+    // the threshold, so menus and pages that are still animating are detected in their final
+    // layout. Gives up after max_delay_ms and returns the latest frame. Without PipeWire a
+    // single screenshot is taken, the fallbacks are too slow to compare frames.
+    pub async fn take_settled_capture(&mut self, settle: &crate::config::SettleConfig) -> Result<Capture, Box<dyn std::error::Error>> {
+        let start = std::time::Instant::now();
+        let mut previous = match self.take_capture_pipewire().await {
+            Ok(capture) => capture,
+            Err(_) => return self.take_capture().await,
+        };
+        let mut frames = 1;
+        while start.elapsed() < Duration::from_millis(settle.max_delay_ms) {
+            tokio::time::sleep(Duration::from_millis(settle.interval_ms)).await;
+            let capture = match self.take_capture_pipewire().await {
+                Ok(capture) => capture,
+                Err(_) => break,
+            };
+            frames += 1;
+            let difference = crate::image_utils::frame_difference(&capture.image, &previous.image);
+            previous = capture;
+            if difference <= settle.threshold {
+                log::debug!("Settled after {:?} frames in {:?}", frames, start.elapsed());
+                return Ok(previous);
+            }
+        }
+        log::warn!("Not settled after {:?} frames in {:?}", frames, start.elapsed());
+        Ok(previous)
+    }
+
     async fn start_capturer_if_needed(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         let start = std::time::Instant::now();
         if self.closed.lock().await.clone() {
-            println!("Capturer stopped, starting: {:?}", start.elapsed());
+            log::debug!("Capturer stopped, starting: {:?}", start.elapsed());
 
             let (capturer, rx) = self.start_screenshare().await?;
             let mut pipewire_capturer = self.pipewire_capturer.lock().await;
@@ -144,25 +235,33 @@ impl ScreenshotTool {
             *self.heartbeat_rx.lock().await = Arc::new(Mutex::new(Some(rx)));
             *self.closed.lock().await = false;
         }
-        println!("Capturer started in {:?}", start.elapsed());
+        log::debug!("Capturer started in {:?}", start.elapsed());
 
         return Ok(());
     }
 
-    async fn take_screenshot_pipewire(&mut self) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, Box<dyn std::error::Error>> {
-        println!("Taking screenshot using pipewire");
+    pub async fn take_capture_pipewire(&mut self) -> Result<Capture, Box<dyn std::error::Error>> {
+        log::trace!("Taking screenshot using pipewire");
         let start = std::time::Instant::now();
         self.start_capturer_if_needed().await?;
-        println!("Capturer started: {:?}", start.elapsed());
+        log::debug!("Capturer started: {:?}", start.elapsed());
+        self.take_capture_running().await
+    }
 
+    // A frame of the PipeWire stream if it is running, it is not started again. Starting it can
+    // show the portal dialog, so this is what background work uses.
+    pub async fn take_capture_running(&mut self) -> Result<Capture, Box<dyn std::error::Error>> {
+        if self.closed.lock().await.clone() {
+            return Err(Box::new(Error));
+        }
         let start = std::time::Instant::now();
         match self.pipewire_capturer.lock().await.as_mut() {
             Some(capturer) => {
-                println!("Capturer found: {:?}", start.elapsed());
+                log::trace!("Capturer found: {:?}", start.elapsed());
                 let start = std::time::Instant::now();
-                let screenshot = capturer.take_screenshot().await?;
-                println!("Screenshot taken: {:?}", start.elapsed());
-                return Ok(screenshot);
+                let capture = capturer.take_capture().await?;
+                log::trace!("Screenshot taken: {:?}", start.elapsed());
+                return Ok(capture);
             }
             None => {
                 return Err(Box::new(Error));
@@ -172,13 +271,13 @@ impl ScreenshotTool {
 
     pub async fn start_screenshare(&mut self) -> Result<(PipewireCapturer, tokio::sync::mpsc::Receiver<()>), Box<dyn std::error::Error>> {
         if !scap::is_supported() {
-            println!("❌ Platform not supported");
+            log::warn!("Platform not supported");
             return Err(Box::new(Error));
         }
         if !scap::has_permission() {
-            println!("❌ Permission not granted. Requesting permission...");
+            log::warn!("Permission not granted. Requesting permission...");
             if !scap::request_permission() {
-                println!("❌ Permission denied");
+                log::warn!("Permission denied");
                 return Err(Box::new(Error));
             }
         }
@@ -201,7 +300,7 @@ impl ScreenshotTool {
         };
 
         let needs_capture = Arc::new(Mutex::new(false));
-        let (tx, rx) = tokio::sync::mpsc::channel::<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>>(1);
+        let (tx, rx) = tokio::sync::mpsc::channel::<Capture>(1);
         let (heartbeat_tx, heartbeat_rx) = tokio::sync::mpsc::channel::<()>(1);
 
         // self.needs_screenshot = Some(needs_capture.clone());
@@ -212,21 +311,21 @@ impl ScreenshotTool {
 
         tokio::spawn(async move {
             let mut capturer: Capturer = Capturer::new(options);
-            println!("[Screencapture Thread] Starting capture");
+            log::trace!("Starting capture");
             capturer.start_capture();
-            println!("[Screencapture Thread] Capture started");
+            log::trace!("Capture started");
             let mut last_screenshot_taken = std::time::Instant::now();
             let timeout_duration = Duration::from_secs(10);
             loop {
                 if last_screenshot_taken.elapsed() > timeout_duration {
-                    println!("[Screencapture Thread] Stopping capturer");
+                    log::trace!("Stopping capturer");
                     // close tx
                     capturer.stop_capture();
                     return;
                 }
 
                 if let Err(_) = timeout(Duration::from_secs(2), heartbeat_tx.send(())).await {
-                    println!("Failed to send heartbeat");
+                    log::trace!("Failed to send heartbeat");
                     capturer.stop_capture();
                     return;
                 }
@@ -235,7 +334,7 @@ impl ScreenshotTool {
                 let frame = match frame {
                     Ok(frame) => frame,
                     Err(err) => {
-                        print!("Failed to get frame: {:?}", err);
+                        log::warn!("Failed to get frame: {:?}", err);
                         tokio::time::sleep(Duration::from_secs(100)).await;
                         capturer.stop_capture();
                         return;
@@ -243,61 +342,41 @@ impl ScreenshotTool {
                 };
 
                 if start.elapsed().as_millis() > 1000 {
-                    println!("Frame took too long to receive");
+                    log::warn!("Frame took too long to receive");
                 }
 
                 let should_read = needs_capture.lock().await.clone();
                 if  !should_read {
                     continue;
                 }
-                println!("Read elapsed: {:?}", start.elapsed());
+                log::trace!("Read elapsed: {:?}", start.elapsed());
                 last_screenshot_taken = std::time::Instant::now();
-                println!("[Screencapture Thread] Reading frame");
+                log::trace!("Reading frame");
                 let mut should_read = needs_capture.as_ref().lock().await;
-                println!("Should read: {:?}", *should_read);
+                log::trace!("Should read: {:?}", *should_read);
                 *should_read = false;
-                println!("Should read: {:?}", *should_read);
-                println!("[Screencapture Thread] Frame read");
+                log::trace!("Should read: {:?}", *should_read);
+                log::trace!("Frame read");
 
-                match frame {
-                    Frame::BGRA(frame) => {
-                        println!("BGRA Frame");
-                    }
-                    Frame::BGR0(frame) => {
-                        println!("BGR0 Frame");
-                    }
-                    Frame::RGB(frame) => {
-                        println!("RGB Frame");
-                    }
-                    Frame::RGBx(frame) => {
-                        println!("RGBx Frame");
-                    }
-                    Frame::XBGR(frame) => {
-                        println!("XBGR Frame");
-                    }
-                    Frame::BGRx(frame) => {
-                        // empty frame black with same size
-                        let mut image = image::ImageBuffer::from_fn(frame.width as u32, frame.height as u32, |x, y| {
-                            image::Rgb([0, 0, 0])
-                        });
-                        let start = std::time::Instant::now();
-                        frame.data.chunks_exact(4).enumerate().for_each(|(i, pixel)| {
-                            let x = i % frame.width as usize;
-                            let y = i / frame.width as usize;
-                            let pixel = image::Rgb([pixel[2], pixel[1], pixel[0]]);
-                            image.put_pixel(x as u32, y as u32, pixel);
-                        });
-                        println!("Image creation: {:?}", start.elapsed());
-                        println!("Sending image");
-                        tx.send(image.clone()).await.unwrap();
-                        println!("Image sent");
-                    } 
-                    Frame::YUVFrame(frame) => {
-                        println!("YUV Frame");
-                    }
+                let start = std::time::Instant::now();
+                let converted = match frame {
+                    Frame::BGRA(frame) => convert_packed(&frame.data, frame.width, frame.height, 4, [2, 1, 0]),
+                    Frame::BGR0(frame) => convert_packed(&frame.data, frame.width, frame.height, 4, [2, 1, 0]),
+                    Frame::RGB(frame) => convert_packed(&frame.data, frame.width, frame.height, 3, [0, 1, 2]),
+                    Frame::RGBx(frame) => convert_packed(&frame.data, frame.width, frame.height, 4, [0, 1, 2]),
+                    Frame::XBGR(frame) => convert_packed(&frame.data, frame.width, frame.height, 4, [3, 2, 1]),
+                    Frame::BGRx(frame) => convert_packed(&frame.data, frame.width, frame.height, 4, [2, 1, 0]),
+                    Frame::YUVFrame(frame) => convert_yuv(&frame),
                     _ => {
-                        println!("Frame type not supported");
+                        log::warn!("Frame type not supported");
+                        None
                     }
+                };
+                if let Some((image, luma)) = converted {
+                    log::trace!("Image creation: {:?}", start.elapsed());
+                    log::trace!("Sending image");
+                    tx.send(Capture { image, luma: Some(luma) }).await.unwrap();
+                    log::trace!("Image sent");
                 }
             }
         });
@@ -306,6 +385,34 @@ impl ScreenshotTool {
     }
 }
 
+// Packed frames of bytes_per_pixel bytes, channels are where red, green and blue are in a pixel.
+// The frames do not carry their row stride and rows may be padded (e.g. to 4 bytes for RGB), so
+// the stride is the size of the frame divided by its rows.
+fn convert_packed(data: &[u8], width: i32, height: i32, bytes_per_pixel: usize, channels: [usize; 3]) -> Option<(image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, LumaPlane)> {
+    if width <= 0 || height <= 0 || data.len() % height as usize != 0 || data.len() / height as usize < width as usize * bytes_per_pixel {
+        log::warn!("Frame of {:?} bytes does not hold {:?}x{:?} pixels", data.len(), width, height);
+        return None;
+    }
+    let stride = data.len() / height as usize;
+    let (block, _) = edges::detection_scale(height as u32);
+    Some(edges::convert_frame(data, stride, width as usize, height as usize, bytes_per_pixel, channels, block))
+}
+
+// YUV frames are NV12, with a half resolution plane of interleaved chroma
+fn convert_yuv(frame: &scap::frame::YUVFrame) -> Option<(image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, LumaPlane)> {
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let (luminance_stride, chrominance_stride) = (frame.luminance_stride as usize, frame.chrominance_stride as usize);
+    let chroma_width = (width + 1) / 2 * 2;
+    if frame.width <= 0 || frame.height <= 0 || luminance_stride < width || chrominance_stride < chroma_width
+        || frame.luminance_bytes.len() < luminance_stride * (height - 1) + width
+        || frame.chrominance_bytes.len() < chrominance_stride * ((height + 1) / 2 - 1) + chroma_width {
+        log::warn!("YUV frame does not hold {:?}x{:?} pixels", frame.width, frame.height);
+        return None;
+    }
+    let (block, _) = edges::detection_scale(height as u32);
+    Some(edges::convert_nv12(&frame.luminance_bytes, luminance_stride, &frame.chrominance_bytes, chrominance_stride, width, height, block))
+}
+
 pub fn get_screenshot_tool() -> ScreenshotTool {
     let mut screenshot_tool = ScreenshotTool {
         pipewire_capturer: Arc::new(Mutex::new(None)),
@@ -326,11 +433,11 @@ pub async fn screenshot_portal() -> Result<String, Box<dyn std::error::Error>> {
     {
         Ok(response) => {
             let screenshot = response.uri().clone();
-            println!("[Scoeeenshot] Screenshot taken: {}", screenshot);
+            log::debug!("Screenshot taken: {}", screenshot);
             return Ok(String::from(screenshot.to_string().replace("file://", "")));
         }
         Err(err) => {
-            println!("[Screenshot] Failed to take screenshot: {}", err);
+            log::warn!("Failed to take screenshot: {}", err);
             return Err(Box::new(Error));
         }
     }
@@ -349,4 +456,32 @@ pub async fn screenshot_xcap() -> Result<String, Box<dyn std::error::Error>> {
             return Err(Box::new(Error));
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packed_rows_are_padded() {
+        // 3x2 RGB with rows padded from 9 to 12 bytes
+        let mut data = vec![0u8; 24];
+        for y in 0..2 {
+            for x in 0..3 {
+                data[y * 12 + x * 3..][..3].copy_from_slice(&[x as u8 * 10, y as u8 * 10, 7]);
+            }
+        }
+        let (image, _) = convert_packed(&data, 3, 2, 3, [0, 1, 2]).unwrap();
+        for (x, y, pixel) in image.enumerate_pixels() {
+            assert_eq!(pixel.0, [x as u8 * 10, y as u8 * 10, 7]);
+        }
+    }
+
+    #[test]
+    fn packed_size_mismatch() {
+        // too short, and not a whole number of rows
+        assert!(convert_packed(&[0u8; 17], 3, 2, 3, [0, 1, 2]).is_none());
+        assert!(convert_packed(&[0u8; 19], 3, 2, 3, [0, 1, 2]).is_none());
+        assert!(convert_packed(&[0u8; 18], 3, 2, 3, [0, 1, 2]).is_some());
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::VecDeque;
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+// how many runs the Latency query returns
+const MAX_TRACES: usize = 32;
+
+// the run being traced and the last finished ones
+static TRACES: Lazy<std::sync::Mutex<Traces>> = Lazy::new(|| std::sync::Mutex::new(Traces { next_id: 1, current: None, traces: VecDeque::new() }));
+
+struct Traces {
+    next_id: u32,
+    current: Option<u32>,
+    traces: VecDeque<Trace>,
+}
+
+// A stage of a run. Times are wall clock microseconds, so the stages the overlay reports line
+// up with the daemon's.
+#[derive(Debug, Clone, Serialize)]
+pub struct Span {
+    pub stage: String,
+    pub start_us: u64,
+    pub end_us: u64,
+}
+
+// One invocation, from the D-Bus call to the first overlay frame
+#[derive(Debug, Clone, Serialize)]
+pub struct Trace {
+    pub id: u32,
+    pub mode: String,
+    pub triggered_us: u64,
+    pub spans: Vec<Span>,
+}
+
+impl Trace {
+    // time from the trigger to the end of the last stage
+    pub fn total_us(&self) -> u64 {
+        self.spans.iter().map(|span| span.end_us).max().unwrap_or(self.triggered_us).saturating_sub(self.triggered_us)
+    }
+
+    // every stage with its duration and when it started after the trigger
+    pub fn breakdown(&self) -> String {
+        let stages = self.spans.iter().map(|span| {
+            format!("{} {:.1}ms (+{:.1}ms)", span.stage, (span.end_us.saturating_sub(span.start_us)) as f64 / 1000.0, span.start_us.saturating_sub(self.triggered_us) as f64 / 1000.0)
+        }).collect::<Vec<String>>();
+        format!("#{} {} {:.1}ms: {}", self.id, self.mode, self.total_us() as f64 / 1000.0, stages.join(", "))
+    }
+}
+
+pub fn now_us() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|now| now.as_micros() as u64).unwrap_or(0)
+}
+
+// Starts the trace of a run, stages recorded until end are part of it
+pub fn begin(mode: &str, triggered_us: u64) -> u32 {
+    let mut traces = TRACES.lock().unwrap();
+    let id = traces.next_id;
+    traces.next_id += 1;
+    traces.current = Some(id);
+    traces.traces.push_back(Trace { id, mode: mode.to_string(), triggered_us, spans: Vec::new() });
+    while traces.traces.len() > MAX_TRACES {
+        traces.traces.pop_front();
+    }
+    id
+}
+
+// Stops recording stages of the daemon into the run, the overlay may still report its own
+pub fn end() {
+    let mut traces = TRACES.lock().unwrap();
+    let current = match traces.current.take() {
+        Some(current) => current,
+        None => return,
+    };
+    if let Some(trace) = traces.traces.iter().find(|trace| trace.id == current) {
+        log::info!("{}", trace.breakdown());
+    }
+}
+
+// Records a stage that started at start_us and ends now. Outside of a run (e.g. prefetching)
+// nothing is recorded.
+pub fn record(stage: &str, start_us: u64) {
+    let current = match TRACES.lock().unwrap().current {
+        Some(current) => current,
+        None => return,
+    };
+    record_span(current, stage, start_us, now_us());
+}
+
+// Records a stage of a run by its id, used for the stages the overlay reports
+pub fn record_span(id: u32, stage: &str, start_us: u64, end_us: u64) {
+    let mut traces = TRACES.lock().unwrap();
+    if let Some(trace) = traces.traces.iter_mut().find(|trace| trace.id == id) {
+        trace.spans.push(Span { stage: stage.to_string(), start_us, end_us });
+    }
+}
+
+// the kept runs as JSON, newest last
+pub fn to_json() -> String {
+    let traces = TRACES.lock().unwrap();
+    serde_json::to_string(&traces.traces).unwrap_or_default()
+}
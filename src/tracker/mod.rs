@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::detection::{Rect, Screenshot};
+
+// An element that was hinted before, with the slot its label was derived from
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Tracked {
+    rect: Rect,
+    hash: u64,
+    slot: u32,
+    last_seen: u64,
+}
+
+// Hands out label slots so the same element keeps its hint across invocations. Slots are kept
+// per application and per hint list (boxes, lines, ...), the GUI turns a slot into letters.
+pub struct Tracker {
+    config: config::LabelConfig,
+    // application -> list -> elements
+    state: HashMap<String, HashMap<String, Vec<Tracked>>>,
+    // the elements of the running invocation per application and list, until it completes
+    pending: HashMap<(String, String), Vec<Tracked>>,
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+// 64 bit average hash of the element, robust against small rendering differences
+fn appearance_hash(screenshot: &Screenshot, (min_x, min_y, max_x, max_y): Rect) -> u64 {
+    let max_x = max_x.min(screenshot.width() as usize - 1);
+    let max_y = max_y.min(screenshot.height() as usize - 1);
+    if max_x <= min_x || max_y <= min_y {
+        return 0;
+    }
+    let crop = image::imageops::crop_imm(screenshot, min_x as u32, min_y as u32, (max_x - min_x + 1) as u32, (max_y - min_y + 1) as u32);
+    let small = image::imageops::resize(&*crop, 8, 8, FilterType::Triangle);
+    let gray = small.pixels().map(|pixel| pixel.0[0] as u32 + pixel.0[1] as u32 + pixel.0[2] as u32).collect::<Vec<u32>>();
+    let mean = gray.iter().sum::<u32>() / gray.len() as u32;
+    gray.iter().enumerate().fold(0u64, |hash, (bit, value)| if *value > mean { hash | (1 << bit) } else { hash })
+}
+
+fn center(rect: &Rect) -> (f32, f32) {
+    ((rect.0 + rect.2) as f32 / 2.0, (rect.1 + rect.3) as f32 / 2.0)
+}
+
+fn similar_size(a: &Rect, b: &Rect) -> bool {
+    let ratio = |a: usize, b: usize| (a.max(1) as f32 / b.max(1) as f32).max(b.max(1) as f32 / a.max(1) as f32);
+    ratio(a.2 - a.0, b.2 - b.0) <= 1.25 && ratio(a.3 - a.1, b.3 - b.1) <= 1.25
+}
+
+impl Tracker {
+    pub fn new(label_config: &config::LabelConfig) -> Tracker {
+        Tracker { config: label_config.clone(), state: HashMap::new(), pending: HashMap::new() }
+    }
+
+    pub fn load(label_config: &config::LabelConfig) -> Tracker {
+        let mut tracker = Tracker::new(label_config);
+        tracker.state = config::labels_path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        tracker.expire();
+        tracker
+    }
+
+    fn expire(&mut self) {
+        let oldest = now().saturating_sub(self.config.ttl_secs);
+        for lists in self.state.values_mut() {
+            for tracked in lists.values_mut() {
+                tracked.retain(|element| element.last_seen >= oldest);
+            }
+            lists.retain(|_, tracked| !tracked.is_empty());
+        }
+        self.state.retain(|_, lists| !lists.is_empty());
+    }
+
+    // The slot of every rect. Rects that match an element seen before get its slot back, the
+    // others get the lowest free slots below capacity (the number of labels the list has). They
+    // are matched against the completed invocations only, and only kept once commit is called,
+    // so every update of a list during a run starts from the same state.
+    pub fn assign(&mut self, app: &str, list: &str, capacity: u32, screenshot: &Screenshot, rects: &[Rect]) -> Vec<u32> {
+        if !self.config.stable {
+            return (0..rects.len() as u32).collect::<Vec<u32>>();
+        }
+        let diagonal = ((screenshot.width() as f32).powi(2) + (screenshot.height() as f32).powi(2)).sqrt();
+        let max_distance = diagonal * self.config.max_distance;
+        let hashes = rects.iter().map(|rect| appearance_hash(screenshot, *rect)).collect::<Vec<u64>>();
+        let previous = self.state.get(app).and_then(|lists| lists.get(list)).map_or(&[][..], |tracked| tracked.as_slice());
+
+        // every plausible pair, closest first, so each previous element goes to its best match
+        let mut pairs: Vec<(f32, usize, usize)> = Vec::new();
+        for (index, rect) in rects.iter().enumerate() {
+            for (previous_index, tracked) in previous.iter().enumerate() {
+                if !similar_size(rect, &tracked.rect) {
+                    continue;
+                }
+                let hash_distance = (hashes[index] ^ tracked.hash).count_ones();
+                if hash_distance > self.config.max_hash_distance {
+                    continue;
+                }
+                let (x, y) = center(rect);
+                let (previous_x, previous_y) = center(&tracked.rect);
+                let distance = ((x - previous_x).powi(2) + (y - previous_y).powi(2)).sqrt();
+                if distance > max_distance {
+                    continue;
+                }
+                // appearance breaks ties between elements at the same distance
+                pairs.push((distance + hash_distance as f32, index, previous_index));
+            }
+        }
+        pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut slots: Vec<Option<u32>> = vec![None; rects.len()];
+        let mut claimed = vec![false; previous.len()];
+        for (_, index, previous_index) in pairs {
+            if slots[index].is_some() || claimed[previous_index] {
+                continue;
+            }
+            slots[index] = Some(previous[previous_index].slot);
+            claimed[previous_index] = true;
+        }
+
+        // Slots of elements that are not on screen now stay reserved until they expire, as long
+        // as there are enough labels left. The most recently seen ones are kept.
+        let mut reserved = previous.iter().enumerate().filter(|(previous_index, _)| !claimed[*previous_index]).map(|(_, tracked)| tracked.clone()).collect::<Vec<Tracked>>();
+        reserved.sort_by_key(|tracked| std::cmp::Reverse(tracked.last_seen));
+        reserved.truncate((capacity as usize).saturating_sub(rects.len()));
+        let mut taken = reserved.iter().map(|tracked| tracked.slot).chain(slots.iter().flatten().cloned()).collect::<std::collections::HashSet<u32>>();
+        let mut next = 0;
+        let slots = slots.into_iter().map(|slot| match slot {
+            Some(slot) => slot,
+            None => {
+                while taken.contains(&next) {
+                    next += 1;
+                }
+                taken.insert(next);
+                next
+            }
+        }).collect::<Vec<u32>>();
+
+        let seen = now();
+        let mut tracked = reserved;
+        for (index, rect) in rects.iter().enumerate() {
+            tracked.push(Tracked { rect: *rect, hash: hashes[index], slot: slots[index], last_seen: seen });
+        }
+        self.pending.insert((app.to_string(), list.to_string()), tracked);
+        slots
+    }
+
+    // Keeps the slots of the invocation, once it completed, and forgets the elements not seen
+    // for ttl_secs
+    pub fn commit(&mut self) {
+        for ((app, list), tracked) in self.pending.drain() {
+            self.state.entry(app).or_default().insert(list, tracked);
+        }
+        self.expire();
+    }
+
+    // A cancelled invocation does not reserve any slots
+    pub fn discard(&mut self) {
+        self.pending.clear();
+    }
+
+    pub fn save(&self) {
+        let path = match config::labels_path() {
+            Some(path) => path,
+            None => return,
+        };
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        match serde_json::to_string(&self.state) {
+            Ok(json) => {
+                if let Err(err) = std::fs::write(&path, json) {
+                    log::warn!("Failed to save {:?}: {}", path, err);
+                }
+            }
+            Err(err) => log::warn!("Failed to save {:?}: {}", path, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // elements are dark on the left half, or on the right half when flipped, on a white screen
+    fn screen(elements: &[(Rect, bool)]) -> Screenshot {
+        let mut screen = Screenshot::from_pixel(400, 300, image::Rgb([255, 255, 255]));
+        for ((min_x, min_y, max_x, max_y), flipped) in elements {
+            for x in *min_x..=*max_x {
+                for y in *min_y..=*max_y {
+                    if ((x - min_x) * 2 < max_x - min_x) != *flipped {
+                        screen.put_pixel(x as u32, y as u32, image::Rgb([20, 20, 20]));
+                    }
+                }
+            }
+        }
+        screen
+    }
+
+    fn assign(tracker: &mut Tracker, app: &str, list: &str, capacity: u32, rects: &[Rect]) -> Vec<u32> {
+        let screen = screen(&rects.iter().map(|rect| (*rect, false)).collect::<Vec<(Rect, bool)>>());
+        tracker.assign(app, list, capacity, &screen, rects)
+    }
+
+    const A: Rect = (10, 10, 49, 29);
+    const B: Rect = (100, 10, 139, 29);
+    const C: Rect = (200, 100, 239, 119);
+
+    #[test]
+    fn same_rects_keep_their_slots() {
+        let mut tracker = Tracker::new(&config::LabelConfig::default());
+        assert_eq!(assign(&mut tracker, "app", "boxes", 10, &[A, B, C]), vec![0, 1, 2]);
+        tracker.commit();
+        assert_eq!(assign(&mut tracker, "app", "boxes", 10, &[A, B, C]), vec![0, 1, 2]);
+        assert_eq!(assign(&mut tracker, "app", "boxes", 10, &[C, A, B]), vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn small_moves_keep_their_slots() {
+        let mut tracker = Tracker::new(&config::LabelConfig::default());
+        assign(&mut tracker, "app", "boxes", 10, &[A, B]);
+        tracker.commit();
+        // well within 5% of the diagonal, and one pixel wider
+        let moved = (A.0 + 6, A.1 + 4, A.2 + 7, A.3 + 4);
+        assert_eq!(assign(&mut tracker, "app", "boxes", 10, &[B, moved]), vec![1, 0]);
+    }
+
+    #[test]
+    fn changed_elements_get_new_slots() {
+        let mut tracker = Tracker::new(&config::LabelConfig::default());
+        assign(&mut tracker, "app", "boxes", 10, &[A, B]);
+        tracker.commit();
+        // twice as wide
+        let wider = (A.0, A.1, A.2 + 40, A.3);
+        assert_eq!(assign(&mut tracker, "app", "boxes", 10, &[wider, B]), vec![2, 1]);
+        // same place and size, but looks different
+        let flipped = screen(&[(A, true), (B, false)]);
+        assert_eq!(tracker.assign("app", "boxes", 10, &flipped, &[A, B]), vec![2, 1]);
+    }
+
+    #[test]
+    fn reserved_slots_expire() {
+        let label_config = config::LabelConfig::default();
+        let mut tracker = Tracker::new(&label_config);
+        assign(&mut tracker, "app", "boxes", 10, &[A]);
+        tracker.commit();
+        // A is gone but keeps its slot
+        assert_eq!(assign(&mut tracker, "app", "boxes", 10, &[C]), vec![1]);
+        tracker.commit();
+        for tracked in tracker.state.get_mut("app").unwrap().get_mut("boxes").unwrap() {
+            if tracked.rect == A {
+                tracked.last_seen = now() - label_config.ttl_secs - 1;
+            }
+        }
+        tracker.commit();
+        assert_eq!(assign(&mut tracker, "app", "boxes", 10, &[B, C]), vec![0, 1]);
+    }
+
+    #[test]
+    fn slots_stay_below_capacity() {
+        let mut tracker = Tracker::new(&config::LabelConfig::default());
+        assign(&mut tracker, "app", "boxes", 3, &[A, B, C]);
+        tracker.commit();
+        // three other elements, the reserved slots give way
+        let others = [(10, 200, 49, 219), (100, 200, 139, 219), (200, 200, 239, 219)];
+        let mut slots = assign(&mut tracker, "app", "boxes", 3, &others);
+        slots.sort();
+        assert_eq!(slots, vec![0, 1, 2]);
+        tracker.commit();
+        // the list shrinks, the rest is still reserved
+        assert_eq!(assign(&mut tracker, "app", "boxes", 3, &[others[2]]), vec![2]);
+        assert!(assign(&mut tracker, "app", "boxes", 3, &[A]).iter().all(|slot| *slot < 3));
+    }
+
+    #[test]
+    fn state_is_kept_per_app_and_list() {
+        let mut tracker = Tracker::new(&config::LabelConfig::default());
+        assign(&mut tracker, "editor", "boxes", 10, &[A]);
+        tracker.commit();
+        assert_eq!(assign(&mut tracker, "terminal", "boxes", 10, &[B]), vec![0]);
+        assert_eq!(assign(&mut tracker, "editor", "lines", 10, &[B]), vec![0]);
+        assert_eq!(assign(&mut tracker, "editor", "boxes", 10, &[B]), vec![1]);
+    }
+
+    #[test]
+    fn cancelled_runs_are_discarded() {
+        let mut tracker = Tracker::new(&config::LabelConfig::default());
+        assign(&mut tracker, "app", "boxes", 10, &[A]);
+        tracker.discard();
+        tracker.commit();
+        assert_eq!(assign(&mut tracker, "app", "boxes", 10, &[B]), vec![0]);
+    }
+}
@@ -0,0 +1,132 @@
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{AtomEnum, ClientMessageEvent, ConfigureWindowAux, ConnectionExt, EventMask, MapState, StackMode};
+use x11rb::rust_connection::RustConnection;
+
+// (min_x, min_y, max_x, max_y)
+type Rect = (usize, usize, usize, usize);
+
+// A top level window on the X server, its rect includes the window manager frame
+#[derive(Debug, Clone)]
+pub struct Window {
+    pub id: u32,
+    pub rect: Rect,
+    pub title: String,
+}
+
+fn atom(conn: &RustConnection, name: &str) -> Result<u32, Box<dyn std::error::Error>> {
+    Ok(conn.intern_atom(false, name.as_bytes())?.reply()?.atom)
+}
+
+fn property32(conn: &RustConnection, window: u32, property: u32, type_: impl Into<u32>) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+    let reply = conn.get_property(false, window, property, type_, 0, u32::MAX)?.reply()?;
+    Ok(reply.value32().map(|values| values.collect::<Vec<u32>>()).unwrap_or_default())
+}
+
+fn title(conn: &RustConnection, window: u32) -> Result<String, Box<dyn std::error::Error>> {
+    let net_wm_name = atom(conn, "_NET_WM_NAME")?;
+    let reply = conn.get_property(false, window, net_wm_name, AtomEnum::ANY, 0, 1024)?.reply()?;
+    if !reply.value.is_empty() {
+        return Ok(String::from_utf8_lossy(&reply.value).to_string());
+    }
+    let reply = conn.get_property(false, window, AtomEnum::WM_NAME, AtomEnum::ANY, 0, 1024)?.reply()?;
+    Ok(String::from_utf8_lossy(&reply.value).to_string())
+}
+
+// the window rect on screen, grown by the frame the window manager draws around it
+fn frame(conn: &RustConnection, root: u32, window: u32, frame_extents: u32) -> Result<(i64, i64, i64, i64), Box<dyn std::error::Error>> {
+    let geometry = conn.get_geometry(window)?.reply()?;
+    let origin = conn.translate_coordinates(window, root, 0, 0)?.reply()?;
+    let mut min_x = origin.dst_x as i64;
+    let mut min_y = origin.dst_y as i64;
+    let mut max_x = min_x + geometry.width as i64 - 1;
+    let mut max_y = min_y + geometry.height as i64 - 1;
+    // left, right, top, bottom
+    let extents = property32(conn, window, frame_extents, AtomEnum::CARDINAL)?;
+    if extents.len() == 4 {
+        min_x -= extents[0] as i64;
+        max_x += extents[1] as i64;
+        min_y -= extents[2] as i64;
+        max_y += extents[3] as i64;
+    }
+    Ok((min_x, min_y, max_x, max_y))
+}
+
+// Mapped top level windows, bottom to top, clipped to the screen. Windows that are completely
+// covered by a single window above them are left out.
+pub fn list() -> Result<Vec<Window>, Box<dyn std::error::Error>> {
+    let (conn, screen_num) = x11rb::connect(None)?;
+    let screen = &conn.setup().roots[screen_num];
+    let root = screen.root;
+    let screen_width = screen.width_in_pixels as i64;
+    let screen_height = screen.height_in_pixels as i64;
+
+    // the window manager's list holds the client windows, without it use the children of the root
+    let mut ids = property32(&conn, root, atom(&conn, "_NET_CLIENT_LIST_STACKING")?, AtomEnum::WINDOW)?;
+    if ids.is_empty() {
+        ids = conn.query_tree(root)?.reply()?.children;
+    }
+    let frame_extents = atom(&conn, "_NET_FRAME_EXTENTS")?;
+
+    let mut windows: Vec<Window> = Vec::new();
+    for id in ids {
+        let attributes = match conn.get_window_attributes(id)?.reply() {
+            Ok(attributes) => attributes,
+            // the window was destroyed in the meantime
+            Err(_) => continue,
+        };
+        if attributes.map_state != MapState::VIEWABLE || attributes.override_redirect {
+            continue;
+        }
+        let (min_x, min_y, max_x, max_y) = match frame(&conn, root, id, frame_extents) {
+            Ok(rect) => rect,
+            Err(_) => continue,
+        };
+        let min_x = min_x.max(0);
+        let min_y = min_y.max(0);
+        let max_x = max_x.min(screen_width - 1);
+        let max_y = max_y.min(screen_height - 1);
+        if max_x <= min_x || max_y <= min_y {
+            continue;
+        }
+        windows.push(Window {
+            id,
+            rect: (min_x as usize, min_y as usize, max_x as usize, max_y as usize),
+            title: title(&conn, id).unwrap_or_default(),
+        });
+    }
+
+    let covered = |index: usize| {
+        let rect = windows[index].rect;
+        windows[index + 1..].iter().any(|above| above.rect.0 <= rect.0 && above.rect.1 <= rect.1 && above.rect.2 >= rect.2 && above.rect.3 >= rect.3)
+    };
+    let visible = (0..windows.len()).filter(|index| !covered(*index)).collect::<Vec<usize>>();
+    Ok(visible.into_iter().map(|index| windows[index].clone()).collect::<Vec<Window>>())
+}
+
+// The class of the focused window (e.g. "firefox"), to keep state per application
+pub fn active_application() -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let (conn, screen_num) = x11rb::connect(None)?;
+    let root = conn.setup().roots[screen_num].root;
+    let active = property32(&conn, root, atom(&conn, "_NET_ACTIVE_WINDOW")?, AtomEnum::WINDOW)?;
+    let window = match active.first() {
+        Some(window) if *window != 0 => *window,
+        _ => return Ok(None),
+    };
+    // WM_CLASS is the instance and the class, each terminated by a null byte
+    let reply = conn.get_property(false, window, AtomEnum::WM_CLASS, AtomEnum::STRING, 0, 1024)?.reply()?;
+    let class = reply.value.split(|byte| *byte == 0).filter(|part| !part.is_empty()).last().map(|class| String::from_utf8_lossy(class).to_string());
+    Ok(class)
+}
+
+// Asks the window manager to focus the window and raises it
+pub fn activate(id: u32) -> Result<(), Box<dyn std::error::Error>> {
+    let (conn, screen_num) = x11rb::connect(None)?;
+    let root = conn.setup().roots[screen_num].root;
+    // source indication 2: the request comes from a pager, not an application
+    let event = ClientMessageEvent::new(32, id, atom(&conn, "_NET_ACTIVE_WINDOW")?, [2u32, 0, 0, 0, 0]);
+    conn.send_event(false, root, EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY, event)?;
+    conn.configure_window(id, &ConfigureWindowAux::new().stack_mode(StackMode::ABOVE))?;
+    conn.flush()?;
+    log::debug!("Activated {:?}", id);
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use swiftmouse::accessibility;
+use zbus::blocking::connection::Builder;
+use zbus::zvariant::OwnedObjectPath;
+
+mod common;
+
+const ROOT_PATH: &str = "/org/a11y/atspi/accessible/root";
+const ACTIVE: u32 = 1 << 1;
+const SHOWING: u32 = 1 << 25;
+
+// An object of the mock accessibility tree
+struct Accessible {
+    role: u32,
+    state: u32,
+    children: Vec<(String, OwnedObjectPath)>,
+}
+
+#[zbus::interface(name = "org.a11y.atspi.Accessible")]
+impl Accessible {
+    fn get_children(&self) -> Vec<(String, OwnedObjectPath)> {
+        self.children.clone()
+    }
+
+    fn get_state(&self) -> Vec<u32> {
+        vec![self.state, 0]
+    }
+
+    fn get_role(&self) -> u32 {
+        self.role
+    }
+}
+
+struct Component {
+    extents: (i32, i32, i32, i32),
+}
+
+#[zbus::interface(name = "org.a11y.atspi.Component")]
+impl Component {
+    fn get_extents(&self, _coord_type: u32) -> (i32, i32, i32, i32) {
+        self.extents
+    }
+}
+
+// The root of an application, which has a name
+struct Application {
+    name: String,
+    children: Vec<(String, OwnedObjectPath)>,
+}
+
+#[zbus::interface(name = "org.a11y.atspi.Accessible")]
+impl Application {
+    fn get_children(&self) -> Vec<(String, OwnedObjectPath)> {
+        self.children.clone()
+    }
+
+    #[zbus(property)]
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+fn child(bus: &str, path: &str) -> (String, OwnedObjectPath) {
+    (bus.to_string(), OwnedObjectPath::try_from(path).unwrap())
+}
+
+// A registry with one application on a private bus. The application has a button, a hidden
+// panel whose link must not be collected, and an embedded widget that lives on a connection of
+// its own, like an out-of-process plug. Needs dbus-daemon and skips without it.
+#[test]
+fn collects_across_connections() {
+    let bus = match common::start_bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let address = bus.address.as_str();
+
+    let plug = Builder::address(address).unwrap().build().unwrap();
+    let plug_name = plug.unique_name().unwrap().to_string();
+    plug.object_server().at("/plug", Accessible { role: 0, state: SHOWING, children: vec![child(&plug_name, "/plug/link")] }).unwrap();
+    plug.object_server().at("/plug/link", Accessible { role: accessibility::ROLE_LINK, state: SHOWING, children: Vec::new() }).unwrap();
+    plug.object_server().at("/plug/link", Component { extents: (40, 50, 60, 20) }).unwrap();
+
+    let application = Builder::address(address).unwrap().build().unwrap();
+    let application_name = application.unique_name().unwrap().to_string();
+    let server = application.object_server();
+    server.at(ROOT_PATH, Accessible {
+        role: 75,
+        state: SHOWING,
+        children: vec![child(&application_name, "/button"), child(&application_name, "/hidden"), child(&plug_name, "/plug")],
+    }).unwrap();
+    server.at("/button", Accessible { role: accessibility::ROLE_PUSH_BUTTON, state: SHOWING, children: Vec::new() }).unwrap();
+    server.at("/button", Component { extents: (10, 20, 30, 40) }).unwrap();
+    server.at("/hidden", Accessible { role: 39, state: 0, children: vec![child(&application_name, "/hidden/link")] }).unwrap();
+    server.at("/hidden/link", Accessible { role: accessibility::ROLE_LINK, state: SHOWING, children: Vec::new() }).unwrap();
+    server.at("/hidden/link", Component { extents: (0, 0, 5, 5) }).unwrap();
+
+    let _registry = Builder::address(address).unwrap()
+        .name("org.a11y.atspi.Registry").unwrap()
+        .serve_at(ROOT_PATH, Accessible { role: 14, state: SHOWING, children: vec![child(&application_name, ROOT_PATH)] }).unwrap()
+        .build()
+        .unwrap();
+
+    let connection = accessibility::connect_to(address).unwrap();
+    let applications = accessibility::applications(&connection).unwrap();
+    assert_eq!(applications.len(), 1);
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let mut elements = accessibility::collect_actionable(&connection, &applications[0], deadline, 100).into_iter().map(|element| (element.role, element.extents)).collect::<Vec<(u32, (i32, i32, i32, i32))>>();
+    elements.sort();
+    assert_eq!(elements, vec![(accessibility::ROLE_PUSH_BUTTON, (10, 20, 30, 40)), (accessibility::ROLE_LINK, (40, 50, 60, 20))]);
+
+    // the budget ends the walk
+    assert!(accessibility::collect_actionable(&connection, &applications[0], deadline, 1).len() <= 1);
+}
+
+// Two applications, the window of the second is active. It has a menu open and a second window
+// in the background.
+#[test]
+fn finds_the_focused_windows() {
+    let bus = match common::start_bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let address = bus.address.as_str();
+    let editor = Builder::address(address).unwrap().build().unwrap();
+    let editor_name = editor.unique_name().unwrap().to_string();
+    editor.object_server().at(ROOT_PATH, Application { name: "editor".to_string(), children: vec![child(&editor_name, "/window")] }).unwrap();
+    editor.object_server().at("/window", Accessible { role: 23, state: SHOWING, children: Vec::new() }).unwrap();
+
+    let terminal = Builder::address(address).unwrap().build().unwrap();
+    let terminal_name = terminal.unique_name().unwrap().to_string();
+    terminal.object_server().at(ROOT_PATH, Application {
+        name: "terminal".to_string(),
+        children: vec![child(&terminal_name, "/background"), child(&terminal_name, "/window"), child(&terminal_name, "/menu")],
+    }).unwrap();
+    terminal.object_server().at("/background", Accessible { role: 23, state: SHOWING, children: Vec::new() }).unwrap();
+    terminal.object_server().at("/window", Accessible { role: 23, state: SHOWING | ACTIVE, children: Vec::new() }).unwrap();
+    terminal.object_server().at("/menu", Accessible { role: 69, state: SHOWING, children: Vec::new() }).unwrap();
+
+    let _registry = Builder::address(address).unwrap()
+        .name("org.a11y.atspi.Registry").unwrap()
+        .serve_at(ROOT_PATH, Accessible { role: 14, state: SHOWING, children: vec![child(&editor_name, ROOT_PATH), child(&terminal_name, ROOT_PATH)] }).unwrap()
+        .build()
+        .unwrap();
+
+    let connection = accessibility::connect_to(address).unwrap();
+    assert_eq!(accessibility::active_application(&connection).unwrap(), Some("terminal".to_string()));
+    let (_, windows) = accessibility::focused_windows(&connection).unwrap().unwrap();
+    assert_eq!(windows, vec![child(&terminal_name, "/window"), child(&terminal_name, "/menu")]);
+
+    // nothing is focused
+    terminal.object_server().remove::<Accessible, _>("/window").unwrap();
+    terminal.object_server().at("/window", Accessible { role: 23, state: SHOWING, children: Vec::new() }).unwrap();
+    assert_eq!(accessibility::active_application(&connection).unwrap(), None);
+}
@@ -0,0 +1,29 @@
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+
+// A private session bus, stopped when dropped so it also goes away when an assertion fails
+pub struct Bus {
+    process: Child,
+    pub address: String,
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+// Starts a bus, None without dbus-daemon, the test is skipped then
+pub fn start_bus() -> Option<Bus> {
+    let mut process = match Command::new("dbus-daemon").args(["--session", "--nofork", "--print-address"]).stdout(Stdio::piped()).spawn() {
+        Ok(process) => process,
+        Err(_) => {
+            eprintln!("Skipped, dbus-daemon is not installed");
+            return None;
+        }
+    };
+    let mut address = String::new();
+    BufReader::new(process.stdout.take().unwrap()).read_line(&mut address).unwrap();
+    Some(Bus { process, address: address.trim().to_string() })
+}
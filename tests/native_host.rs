@@ -0,0 +1,90 @@
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use swiftmouse::browser;
+
+mod common;
+
+// Stands in for the daemon, records the pushed elements or rejects them
+struct Daemon {
+    pushed: Arc<Mutex<Vec<Vec<browser::BrowserElement>>>>,
+    reject: Arc<AtomicBool>,
+}
+
+#[zbus::interface(name = "com.quexten.swiftmouse")]
+impl Daemon {
+    fn push_browser_elements(&self, elements: String) -> zbus::fdo::Result<u32> {
+        if self.reject.load(Ordering::SeqCst) {
+            return Err(zbus::fdo::Error::InvalidArgs("rejected".to_string()));
+        }
+        let elements: Vec<browser::BrowserElement> = serde_json::from_str(&elements).unwrap();
+        let len = elements.len() as u32;
+        self.pushed.lock().unwrap().push(elements);
+        Ok(len)
+    }
+}
+
+// sends a message like the browser does and returns the host's reply
+fn exchange(stdin: &mut std::process::ChildStdin, stdout: &mut std::process::ChildStdout, message: &str) -> serde_json::Value {
+    browser::write_message(stdin, message).unwrap();
+    let reply = browser::read_message(stdout).unwrap().unwrap();
+    serde_json::from_str(&reply).unwrap()
+}
+
+// The test is the browser: it starts the host and writes messages to it like the extension. The
+// daemon is a stand-in on a private bus, so it needs dbus-daemon and skips without it.
+#[test]
+fn forwards_elements_and_errors() {
+    let bus = match common::start_bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let address = bus.address.as_str();
+
+    let pushed = Arc::new(Mutex::new(Vec::new()));
+    let reject = Arc::new(AtomicBool::new(false));
+    let daemon = Daemon { pushed: pushed.clone(), reject: reject.clone() };
+    let _connection = zbus::blocking::connection::Builder::address(address).unwrap()
+        .name("com.quexten.swiftmouse").unwrap()
+        .serve_at("/com/quexten/swiftmouse", daemon).unwrap()
+        .build()
+        .unwrap();
+
+    let mut host = std::process::Command::new(env!("CARGO_BIN_EXE_native-host"))
+        .env("DBUS_SESSION_BUS_ADDRESS", address)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = host.stdin.take().unwrap();
+    let mut stdout = host.stdout.take().unwrap();
+
+    // the empty element is dropped by the host
+    let message = r#"{"window": {"x": 100, "y": 50}, "viewport": {"x": 0, "y": 80}, "scale": 2,
+        "elements": [{"kind": "link", "x": 10, "y": 20, "width": 30, "height": 10},
+                     {"kind": "button", "x": 0, "y": 0, "width": 0, "height": 10}]}"#;
+    let reply = exchange(&mut stdin, &mut stdout, message);
+    assert_eq!(reply["ok"], true);
+    assert_eq!(reply["elements"], 1);
+    {
+        let pushed = pushed.lock().unwrap();
+        assert_eq!(pushed.len(), 1);
+        let element = &pushed[0][0];
+        assert!(element.link);
+        assert_eq!((element.min_x, element.min_y, element.max_x, element.max_y), (220, 300, 280, 320));
+    }
+
+    reject.store(true, Ordering::SeqCst);
+    let reply = exchange(&mut stdin, &mut stdout, message);
+    assert_eq!(reply["ok"], false);
+    assert!(reply["error"].as_str().unwrap().contains("rejected"));
+
+    let reply = exchange(&mut stdin, &mut stdout, "{\"elements\": 3}");
+    assert_eq!(reply["ok"], false);
+    assert_eq!(pushed.lock().unwrap().len(), 1);
+
+    // the browser closing the pipe ends the host
+    drop(stdin);
+    assert!(host.wait().unwrap().success());
+}
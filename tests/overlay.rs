@@ -0,0 +1,62 @@
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixStream;
+use std::process::Stdio;
+use std::time::Duration;
+
+use swiftmouse::overlay;
+use swiftmouse::screenshot;
+
+// Opens and closes the overlay window through the daemon's protocol. It needs a desktop session
+// with the remote desktop portal, so it only runs with SWIFTMOUSE_GUI_TESTS=1. Run it in a
+// Wayland session and again on X11 (an X11 session, or WAYLAND_DISPLAY unset for Xwayland):
+//
+//     SWIFTMOUSE_GUI_TESTS=1 cargo test --test overlay
+//     SWIFTMOUSE_GUI_TESTS=1 WAYLAND_DISPLAY= cargo test --test overlay
+#[test]
+fn show_and_hide_cycle() {
+    if std::env::var("SWIFTMOUSE_GUI_TESTS").is_err() {
+        eprintln!("Skipped, set SWIFTMOUSE_GUI_TESTS=1 to run it in a desktop session");
+        return;
+    }
+    let (mut stream, overlay_stream) = UnixStream::pair().unwrap();
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_gui"))
+        .stdin(Stdio::from(OwnedFd::from(overlay_stream)))
+        .spawn()
+        .unwrap();
+    // the first show includes the portal session
+    stream.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+
+    let image = image::ImageBuffer::from_pixel(64, 48, image::Rgb([40u8, 80, 120]));
+    for trace_id in 1..=2 {
+        let frame = screenshot::SharedFrame::new(&image).unwrap();
+        let mut buffer: Vec<u8> = Vec::new();
+        overlay::write_show(&mut buffer, trace_id, frame.width, frame.height);
+        overlay::write_message(&mut stream, overlay::SHOW_ELEMENTS, &buffer, Some(frame.fd())).unwrap();
+        overlay::write_message(&mut stream, overlay::BATCH_DONE, &[], None).unwrap();
+
+        // the window is open once its first frame is drawn
+        loop {
+            let mut message = overlay::read_message(&mut stream).unwrap();
+            assert_ne!(message.tag, overlay::EVENT_HIDDEN, "closed before its first frame");
+            if message.tag == overlay::EVENT_SPAN {
+                let (id, stage, _, _) = overlay::read_span(&mut message.payload).unwrap();
+                if id == trace_id && stage == "overlay_first_frame" {
+                    break;
+                }
+            }
+        }
+
+        overlay::write_message(&mut stream, overlay::HIDE, &[], None).unwrap();
+        loop {
+            let message = overlay::read_message(&mut stream).unwrap();
+            if message.tag == overlay::EVENT_HIDDEN {
+                break;
+            }
+        }
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    }
+
+    drop(stream);
+    let status = child.wait().unwrap();
+    assert!(status.success());
+}
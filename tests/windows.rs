@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use swiftmouse::windows;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{AtomEnum, ChangeWindowAttributesAux, ConfigureWindowAux, ConnectionExt, CreateWindowAux, EventMask, PropMode, StackMode, WindowClass};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+use x11rb::wrapper::ConnectionExt as _;
+
+const DISPLAY: &str = ":97";
+
+fn atom(conn: &RustConnection, name: &str) -> u32 {
+    conn.intern_atom(false, name.as_bytes()).unwrap().reply().unwrap().atom
+}
+
+fn create(conn: &RustConnection, root: u32, (x, y, width, height): (i16, i16, u16, u16), title: &str, override_redirect: bool, map: bool) -> u32 {
+    let id = conn.generate_id().unwrap();
+    let aux = CreateWindowAux::new().override_redirect(override_redirect as u32);
+    conn.create_window(0, id, root, x, y, width, height, 0, WindowClass::INPUT_OUTPUT, 0, &aux).unwrap();
+    conn.change_property8(PropMode::REPLACE, id, AtomEnum::WM_NAME, AtomEnum::STRING, title.as_bytes()).unwrap();
+    conn.change_property8(PropMode::REPLACE, id, AtomEnum::WM_CLASS, AtomEnum::STRING, format!("{}\0Test{}\0", title, title).as_bytes()).unwrap();
+    if map {
+        conn.map_window(id).unwrap();
+    }
+    id
+}
+
+fn titles(windows: &[windows::Window]) -> Vec<&str> {
+    windows.iter().map(|window| window.title.as_str()).collect::<Vec<&str>>()
+}
+
+// Lists and activates windows on a virtual X server without a window manager, so the windows are
+// the children of the root. Needs Xvfb, so it only runs with SWIFTMOUSE_XVFB_TESTS=1:
+//
+//     SWIFTMOUSE_XVFB_TESTS=1 cargo test --test windows
+#[test]
+fn list_and_activate() {
+    if std::env::var("SWIFTMOUSE_XVFB_TESTS").is_err() {
+        eprintln!("Skipped, set SWIFTMOUSE_XVFB_TESTS=1 to run it on Xvfb");
+        return;
+    }
+    let mut server = std::process::Command::new("Xvfb").args([DISPLAY, "-screen", "0", "640x480x24", "-nolisten", "tcp"]).spawn().unwrap();
+    let socket = format!("/tmp/.X11-unix/X{}", &DISPLAY[1..]);
+    let start = std::time::Instant::now();
+    while !std::path::Path::new(&socket).exists() && start.elapsed() < Duration::from_secs(10) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    std::env::set_var("DISPLAY", DISPLAY);
+
+    let (conn, screen_num) = x11rb::connect(Some(DISPLAY)).unwrap();
+    let root = conn.setup().roots[screen_num].root;
+    // bottom to top: covered lies completely under first, unmapped and menu (override redirect)
+    // are never listed
+    let covered = create(&conn, root, (20, 20, 50, 40), "covered", false, true);
+    let first = create(&conn, root, (10, 10, 100, 80), "first", false, true);
+    create(&conn, root, (600, 400, 100, 100), "second", false, true);
+    create(&conn, root, (300, 300, 50, 50), "unmapped", false, false);
+    create(&conn, root, (300, 100, 50, 50), "menu", true, true);
+    conn.sync().unwrap();
+
+    let listed = windows::list().unwrap();
+    assert_eq!(titles(&listed), vec!["first", "second"]);
+    assert_eq!(listed[0].id, first);
+    assert_eq!(listed[0].rect, (10, 10, 109, 89));
+    // clipped to the screen
+    assert_eq!(listed[1].rect, (600, 400, 639, 479));
+
+    // stand in for the window manager's part of the activation
+    conn.change_window_attributes(root, &ChangeWindowAttributesAux::new().event_mask(EventMask::SUBSTRUCTURE_NOTIFY)).unwrap();
+    conn.sync().unwrap();
+    windows::activate(covered).unwrap();
+    let net_active_window = atom(&conn, "_NET_ACTIVE_WINDOW");
+    let mut requested = false;
+    let start = std::time::Instant::now();
+    while !requested && start.elapsed() < Duration::from_secs(5) {
+        match conn.poll_for_event().unwrap() {
+            Some(Event::ClientMessage(event)) => {
+                requested = event.type_ == net_active_window && event.window == covered && event.data.as_data32()[0] == 2;
+            }
+            Some(_) => {}
+            None => std::thread::sleep(Duration::from_millis(10)),
+        }
+    }
+    assert!(requested, "no _NET_ACTIVE_WINDOW request");
+    conn.change_property32(PropMode::REPLACE, root, net_active_window, AtomEnum::WINDOW, &[covered]).unwrap();
+    conn.sync().unwrap();
+
+    // activate raised it, now it is on top and first is no longer covered by anything
+    let listed = windows::list().unwrap();
+    assert_eq!(titles(&listed), vec!["first", "second", "covered"]);
+    assert_eq!(windows::active_application().unwrap(), Some("Testcovered".to_string()));
+
+    // lowering it hides it again
+    conn.configure_window(covered, &ConfigureWindowAux::new().stack_mode(StackMode::BELOW)).unwrap();
+    conn.sync().unwrap();
+    assert_eq!(titles(&windows::list().unwrap()), vec!["first", "second"]);
+
+    drop(conn);
+    server.kill().unwrap();
+    server.wait().unwrap();
+}